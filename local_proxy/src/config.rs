@@ -1,14 +1,44 @@
+use crate::outbound::layer::FragmentMode;
+
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct Config {
     pub proxies: Option<Vec<ProxyConfig>>,
     pub doh: Option<DoHConfig>,
     pub fragment: Option<u8>,
+    pub fragment_options: Option<FragmentConfig>,
+    pub http_obfuscation: Option<HttpObfuscationConfig>,
+    pub fronting: Option<Vec<FrontingConfig>>,
+    pub ech: Option<bool>,
+    pub client_hello: Option<ClientHelloConfig>,
     pub http_listen: Option<Vec<SocketAddr>>,
+    pub https_listen: Option<Vec<SocketAddr>>,
+    pub mitm: Option<MitmConfig>,
     pub tproxy_listen: Option<TProxy>,
     pub dns_listen: Option<Vec<SocketAddr>>,
+    pub doh_listen: Option<Vec<SocketAddr>>,
+    pub dot_listen: Option<Vec<SocketAddr>>,
+    pub tls: Option<TlsServerConfig>,
+    pub stats_listen: Option<Vec<SocketAddr>>,
+    pub dns: Option<DnsConfig>,
+    pub happy_eyeballs: Option<HappyEyeballsConfig>,
+}
+
+impl Config {
+    /// Whether any upstream resolver is configured
+    pub fn has_upstream(&self) -> bool {
+        self.doh.is_some()
+            || self
+                .dns
+                .as_ref()
+                .and_then(|d| d.split.as_ref())
+                .is_some_and(|s| !s.is_empty())
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,16 +47,143 @@ pub struct ProxyConfig {
     pub user: Option<String>,
     pub password: Option<String>,
     pub server: String,
+    pub tls: Option<TlsClientConfig>,
+}
+
+/// Options for the "tls" layers of a proxy
+#[derive(Serialize, Deserialize)]
+pub struct TlsClientConfig {
+    pub ca: Option<String>,
+    pub ca_only: Option<bool>,
+    pub pin_sha256: Option<Vec<String>>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub sni: Option<String>,
+    pub enable_sni: Option<bool>,
+    pub alpn: Option<Vec<String>>,
+    pub min_version: Option<String>,
+    pub client_hello: Option<ClientHelloConfig>,
+}
+
+/// Cipher suites, groups and GREASE ECH offered in TLS ClientHellos
+#[derive(Serialize, Deserialize)]
+pub struct ClientHelloConfig {
+    pub cipher_suites: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    pub grease_ech: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct DoHConfig {
     pub endpoint: String,
     pub fake_host: Option<String>,
+    pub idle_timeout: Option<u64>,
+    pub method: Option<String>,
+    pub user_agent: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    pub fragment_mode: Option<FragmentMode>,
+    pub bootstrap: Option<Vec<IpAddr>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TlsServerConfig {
+    pub cert: String,
+    pub key: String,
+    pub names: Option<Vec<String>>,
+}
+
+/// How the ClientHello is split into TLS records
+#[derive(Serialize, Deserialize)]
+pub struct FragmentConfig {
+    pub mode: Option<String>,
+    pub strategy: Option<String>,
+    pub size: Option<usize>,
+    pub min_size: Option<usize>,
+    pub max_size: Option<usize>,
+    pub count: Option<usize>,
+    pub delay: Option<u64>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub modes: Option<HashMap<String, String>>,
+    pub auto: Option<bool>,
+    pub auto_timeout: Option<u64>,
+    pub auto_ttl: Option<u64>,
+}
+
+/// Rewrites applied to the head of plain HTTP requests
+#[derive(Serialize, Deserialize)]
+pub struct HttpObfuscationConfig {
+    pub host_case: Option<bool>,
+    pub extra_space: Option<bool>,
+    pub remove_space: Option<bool>,
+    pub split: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MitmConfig {
+    pub domains: Vec<String>,
+    pub ca_cert: String,
+    pub ca_key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FrontingConfig {
+    pub domains: Vec<String>,
+    pub front: String,
+}
+
+/// Delays in milliseconds (RFC 8305)
+#[derive(Serialize, Deserialize)]
+pub struct HappyEyeballsConfig {
+    pub head_start: Option<u64>,
+    pub attempt_delay: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DnsConfig {
+    pub hosts: Option<HashMap<String, Vec<IpAddr>>>,
+    pub hosts_file: Option<String>,
+    pub rewrites: Option<Vec<RewriteConfig>>,
+    pub blocklists: Option<Vec<String>>,
+    pub block_response: Option<String>,
+    pub split: Option<Vec<SplitConfig>>,
+    pub ecs: Option<String>,
+    pub padding: Option<bool>,
+    pub dnssec: Option<bool>,
+    pub fake_ip: Option<FakeIpConfig>,
+    pub query_log: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FakeIpConfig {
+    pub ipv4: Option<String>,
+    pub ipv6: Option<String>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SplitConfig {
+    pub domains: Vec<String>,
+    #[serde(flatten)]
+    pub upstream: DoHConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RewriteConfig {
+    pub domain: String,
+    #[serde(rename = "match")]
+    pub match_type: Option<String>,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub value: String,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TProxy {
     pub listen: Vec<SocketAddr>,
     pub redir_type: Option<String>,
+    pub sniff: Option<bool>,
+    pub sniff_timeout: Option<u64>,
+    pub intercept_http: Option<bool>,
 }
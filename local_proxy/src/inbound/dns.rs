@@ -1,10 +1,20 @@
-use crate::{utils::doh_query, Error, PROXY};
+use crate::{
+    utils::{fit_udp, serve_query},
+    Error, PROXY,
+};
 
 use std::{net::SocketAddr, sync::Arc, time::Duration};
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn start() -> Result<(), Error> {
-    let listen = PROXY.get().unwrap().config.dns_listen.as_ref().ok_or("")?;
+    let proxy = PROXY.get().unwrap();
+    let listen = proxy.config.dns_listen.as_ref().ok_or("")?;
     if listen.is_empty() {
         return Ok(());
     }
@@ -39,6 +49,33 @@ pub async fn start() -> Result<(), Error> {
                 }
             }
         });
+
+        // The README's example shares the port with tproxy_listen, which is TCP
+        if let Some(tproxy) = &proxy.config.tproxy_listen {
+            if tproxy.listen.contains(i) {
+                continue;
+            }
+        }
+
+        let listener = match TcpListener::bind(i).await {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("[Warning] DNS over TCP is disabled on {}: {}", i, e);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            loop {
+                let (client, from) = match listener.accept().await {
+                    Ok(o) => o,
+                    Err(_) => continue,
+                };
+                match client.set_nodelay(true) {
+                    Ok(_) => tokio::spawn(run_tcp(client, from)),
+                    Err(_) => continue,
+                };
+            }
+        });
     }
 
     loop {
@@ -51,9 +88,52 @@ async fn run(
     from: SocketAddr,
     sender: &mpsc::Sender<(Vec<u8>, SocketAddr)>,
 ) -> Result<(), Error> {
-    let result = doh_query(buf).await?;
+    let result = serve_query(buf.clone(), from).await?;
+    let result = fit_udp(&buf, result)?;
 
     sender.send((result, from)).await?;
 
     Ok(())
 }
+
+/// Serves RFC 7766 length-prefixed queries, answering pipelined queries as soon as each one resolves
+pub async fn run_tcp<RW>(client: RW, from: SocketAddr) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = io::split(client);
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(64);
+
+    let writer = tokio::spawn(async move {
+        while let Some(response) = receiver.recv().await {
+            let mut message = Vec::with_capacity(response.len() + 2);
+            message.extend_from_slice(&u16::try_from(response.len())?.to_be_bytes());
+            message.extend_from_slice(&response);
+            writer.write_all(&message).await?;
+            writer.flush().await?;
+        }
+        Ok::<_, Error>(())
+    });
+
+    loop {
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, reader.read_u16()).await {
+            Ok(Ok(l)) => l,
+            _ => break,
+        };
+        let mut query = vec![0; len.into()];
+        match tokio::time::timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut query)).await {
+            Ok(Ok(_)) => {}
+            _ => break,
+        }
+
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            if let Ok(response) = serve_query(query, from).await {
+                let _ = sender.send(response).await;
+            }
+        });
+    }
+
+    drop(sender);
+    writer.await?
+}
@@ -0,0 +1,105 @@
+use crate::{
+    utils::{serve_query, tls, Body},
+    Error, PROXY,
+};
+
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use std::{net::SocketAddr, time::Duration};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+pub async fn start() -> Result<(), Error> {
+    let proxy = PROXY.get().unwrap();
+    let listen = proxy.config.doh_listen.as_ref().ok_or("")?;
+    if listen.is_empty() {
+        return Ok(());
+    }
+
+    let tls_config = proxy.config.tls.as_ref().ok_or("")?;
+    let acceptor = TlsAcceptor::from(tls::server_config(tls_config, &[b"h2", b"http/1.1"])?);
+
+    for i in listen {
+        let listener = TcpListener::bind(i).await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, from) = match listener.accept().await {
+                    Ok(o) => o,
+                    Err(_) => continue,
+                };
+                if client.set_nodelay(true).is_err() {
+                    continue;
+                }
+
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let client = tokio::time::timeout(tls::ACCEPT_TIMEOUT, acceptor.accept(client))
+                        .await??;
+                    let client = TokioIo::new(client);
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(client, service_fn(|r| handle(r, from)))
+                        .await
+                });
+            }
+        });
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+    }
+}
+
+async fn handle(request: Request<Incoming>, from: SocketAddr) -> Result<Response<Body>, Error> {
+    if request.uri().path() != "/dns-query" {
+        return status(StatusCode::NOT_FOUND);
+    }
+
+    let query = match *request.method() {
+        Method::GET => {
+            let dns = request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .find_map(|q| q.strip_prefix("dns="));
+            let base64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+            match dns.map(|d| base64.decode(d.trim_end_matches('='))) {
+                Some(Ok(q)) => q,
+                _ => return status(StatusCode::BAD_REQUEST),
+            }
+        }
+        Method::POST => {
+            // A DNS message can not be larger than this
+            match Limited::new(request.into_body(), 65535).collect().await {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+            }
+        }
+        _ => return status(StatusCode::METHOD_NOT_ALLOWED),
+    };
+
+    let response = match serve_query(query, from).await {
+        Ok(o) => o,
+        Err(_) => return status(StatusCode::BAD_GATEWAY),
+    };
+
+    Ok(Response::builder()
+        .header("content-type", "application/dns-message")
+        .body(Body::new(Full::new(Bytes::from(response))))?)
+}
+
+fn status(status: StatusCode) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .body(Body::new(Full::new(Bytes::new())))?)
+}
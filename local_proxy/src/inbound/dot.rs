@@ -0,0 +1,43 @@
+use crate::{inbound::dns, utils::tls, Error, PROXY};
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+pub async fn start() -> Result<(), Error> {
+    let proxy = PROXY.get().unwrap();
+    let listen = proxy.config.dot_listen.as_ref().ok_or("")?;
+    if listen.is_empty() {
+        return Ok(());
+    }
+
+    let tls_config = proxy.config.tls.as_ref().ok_or("")?;
+    let acceptor = TlsAcceptor::from(tls::server_config(tls_config, &[b"dot"])?);
+
+    for i in listen {
+        let listener = TcpListener::bind(i).await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, from) = match listener.accept().await {
+                    Ok(o) => o,
+                    Err(_) => continue,
+                };
+                if client.set_nodelay(true).is_err() {
+                    continue;
+                }
+
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let client = tokio::time::timeout(tls::ACCEPT_TIMEOUT, acceptor.accept(client))
+                        .await??;
+                    dns::run_tcp(client, from).await
+                });
+            }
+        });
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+    }
+}
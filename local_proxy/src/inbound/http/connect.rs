@@ -1,6 +1,7 @@
+use super::mitm;
 use crate::{
     outbound::ProxyOutBoundDefaultMethods,
-    utils::{Body, SocketAddr},
+    utils::{Body, Prefixed, SocketAddr},
     Error, PROXY,
 };
 
@@ -13,8 +14,31 @@ use tokio::io;
 
 pub async fn run(request: Request<Body>) -> Result<Response<Body>, Error> {
     let server = SocketAddr::from_str(&request.uri().to_string())?;
-
     let proxy = PROXY.get().ok_or("")?;
+    // Fronting needs MITM: the client's handshake covers its SNI, which can not be rewritten
+    if proxy.mitm.intercepts(&server.hostname) {
+        tokio::spawn(async move {
+            let mut client = TokioIo::new(hyper::upgrade::on(request).await?);
+            let mut first = Vec::new();
+            let is_tls = mitm::starts_with_client_hello(&mut client, &mut first).await?;
+            let mut client = Prefixed::new(first, client);
+            if is_tls {
+                return mitm::serve(client, server).await;
+            }
+
+            let proxy = PROXY.get().ok_or("")?;
+            let mut proxies = Box::new(proxy.proxy_stack.iter().map(|p| &**p).rev());
+            let mut server_conn = proxies
+                .next()
+                .ok_or("")?
+                .happy_eyeballs(proxies, &server)
+                .await?;
+            let _ = io::copy_bidirectional(&mut client, &mut server_conn).await;
+            Ok::<_, Error>(())
+        });
+        return Ok(Response::new(Body::new(Empty::<Bytes>::new())));
+    }
+
     let mut proxies = Box::new(proxy.proxy_stack.iter().map(|p| &**p).rev());
     let mut server_conn = proxies
         .next()
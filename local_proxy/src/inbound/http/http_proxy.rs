@@ -1,7 +1,7 @@
 use crate::{
-    outbound::{layer::Fragment, ProxyOutBound},
+    outbound::{layer::FragmentMode, proxy_stack},
     utils::{Body, HostName, ParsedUri, SocketAddr},
-    Error, PROXY,
+    Error,
 };
 
 use base64::Engine;
@@ -93,20 +93,7 @@ pub async fn send_request(
 
     *request.uri_mut() = uri.try_into()?;
 
-    let proxy = PROXY.get().ok_or("")?;
-    let mut proxies: Vec<&Box<dyn ProxyOutBound>> = proxy.proxy_stack.iter().collect();
-    let fragment_layer: Box<dyn ProxyOutBound> = Box::new(Fragment::new());
-    if let Some(fragment) = req_conf.fragment {
-        if let Some(2..) | None = proxy.config.fragment {
-            if !fragment {
-                proxies.pop();
-            }
-        } else if fragment {
-            proxies.push(&fragment_layer);
-        }
-    }
-    let mut proxies = Box::new(proxies.into_iter().map(|p| &**p).rev());
-
+    let mut proxies = proxy_stack(req_conf)?;
     let response = proxies
         .next()
         .ok_or("")?
@@ -120,6 +107,7 @@ pub struct RequestConfig {
     pub doh: bool,
     pub fake_host: Option<HostName>,
     pub fragment: Option<bool>,
+    pub fragment_mode: Option<FragmentMode>,
 }
 
 impl RequestConfig {
@@ -128,6 +116,7 @@ impl RequestConfig {
             doh: true,
             fake_host: None,
             fragment: None,
+            fragment_mode: None,
         }
     }
 }
@@ -0,0 +1,138 @@
+//! Terminates the TLS of CONNECT tunnels to selected domains with certificates from a local CA,
+//! so that their requests go through `http_proxy::send_request` like plain HTTP ones.
+
+use super::{bad_gateway, http_proxy};
+use crate::{
+    config::MitmConfig,
+    utils::{tls, x509::Ca, Body, HostName, SocketAddr, SuffixMatcher},
+    Error, PROXY,
+};
+
+use hyper::{body::Incoming, header::HeaderValue, service::service_fn, Request, Response, Uri};
+use hyper_util::rt::TokioIo;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio_rustls::{rustls, TlsAcceptor};
+use ttl_cache::TtlCache;
+
+/// Issued certificates are valid for a year, but are reissued after this
+const CERT_TTL: Duration = Duration::from_secs(86400);
+
+/// Tunnels whose client sends nothing within this are left alone, for server-first protocols
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Mitm {
+    domains: SuffixMatcher,
+    ca: Option<Ca>,
+    configs: Mutex<TtlCache<String, Arc<rustls::ServerConfig>>>,
+}
+
+impl Mitm {
+    pub fn new(config: Option<&MitmConfig>) -> Result<Self, Error> {
+        let (domains, ca) = match config {
+            Some(config) => (
+                SuffixMatcher::from_domains(&config.domains),
+                Some(Ca::load_or_generate(&config.ca_cert, &config.ca_key)?),
+            ),
+            None => (SuffixMatcher::default(), None),
+        };
+
+        Ok(Self {
+            domains,
+            ca,
+            configs: Mutex::new(TtlCache::new(1024)),
+        })
+    }
+
+    pub fn intercepts(&self, hostname: &HostName) -> bool {
+        match hostname {
+            HostName::Domain(d) => self.domains.matches(d),
+            _ => false,
+        }
+    }
+
+    fn server_config(&self, hostname: &HostName) -> Result<Arc<rustls::ServerConfig>, Error> {
+        let name = hostname.to_string();
+        if let Some(config) = self.configs.lock().map_err(|_| "")?.get(&name) {
+            return Ok(Arc::clone(config));
+        }
+
+        let (certs, key) = self
+            .ca
+            .as_ref()
+            .ok_or("")?
+            .issue(std::slice::from_ref(hostname))?;
+        let mut config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let config = Arc::new(config);
+
+        self.configs
+            .lock()
+            .map_err(|_| "")?
+            .insert(name, Arc::clone(&config), CERT_TTL);
+        Ok(config)
+    }
+}
+
+/// Reads from `client` into `buf` until it is known whether the tunnel starts with a TLS ClientHello,
+/// so that other protocols to intercepted domains can be tunneled as they are
+pub async fn starts_with_client_hello<R>(client: &mut R, buf: &mut Vec<u8>) -> Result<bool, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + CLIENT_HELLO_TIMEOUT;
+    // Record header, then the handshake type
+    while buf.len() < 6 {
+        match tokio::time::timeout_at(deadline, client.read_buf(buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(false),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(buf[0] == 0x16 && buf[5] == 0x01)
+}
+
+/// Serves the requests inside the client's TLS, sending them to `server` over https
+pub async fn serve<RW>(client: RW, server: SocketAddr) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mitm = &PROXY.get().ok_or("")?.mitm;
+    let acceptor = TlsAcceptor::from(mitm.server_config(&server.hostname)?);
+    let client = tokio::time::timeout(tls::ACCEPT_TIMEOUT, acceptor.accept(client)).await??;
+    let client = TokioIo::new(client);
+
+    hyper::server::conn::http1::Builder::new()
+        .serve_connection(client, service_fn(|r| handle(r, &server)))
+        .with_upgrades()
+        .await?;
+    Ok(())
+}
+
+/// Requests go to the CONNECT target whatever their Host header says, as the certificate was issued for it
+async fn handle(request: Request<Incoming>, server: &SocketAddr) -> Result<Response<Body>, Error> {
+    let mut request = Body::convert_request(request);
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    *request.uri_mut() = Uri::try_from(format!("https://{}{}", server, path))?;
+
+    let mut response = match http_proxy::run(request).await {
+        Ok(response) => response,
+        Err(_) => return bad_gateway(),
+    };
+    response
+        .headers_mut()
+        .insert("connection", HeaderValue::from_static("keep-alive"));
+    response.headers_mut().remove("keep-alive");
+
+    Ok(response)
+}
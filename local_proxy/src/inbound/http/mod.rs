@@ -1,8 +1,14 @@
 pub mod http_proxy;
 
 mod connect;
+mod mitm;
 
-use crate::{utils::Body, Error, ERROR_HTML, PROXY};
+pub use mitm::Mitm;
+
+use crate::{
+    utils::{tls, Body, HostName, SocketAddr},
+    Error, BLOCKED_HTML, ERROR_HTML, PROXY,
+};
 
 use http_body_util::Full;
 use hyper::{
@@ -12,8 +18,9 @@ use hyper::{
     Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
-use std::time::Duration;
+use std::{str::FromStr, time::Duration};
 use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 
 pub async fn start() -> Result<(), Error> {
     let listen = PROXY.get().unwrap().config.http_listen.as_ref().ok_or("")?;
@@ -49,9 +56,82 @@ pub async fn start() -> Result<(), Error> {
     }
 }
 
-async fn handle(request: Request<Incoming>) -> Result<Response<Body>, Error> {
+/// Serves the same as "http_listen" over TLS, for the HTTPS proxy mode of browsers
+pub async fn start_tls() -> Result<(), Error> {
+    let proxy = PROXY.get().unwrap();
+    let listen = proxy.config.https_listen.as_ref().ok_or("")?;
+    if listen.is_empty() {
+        return Ok(());
+    }
+
+    let (cert, key) = match &proxy.config.tls {
+        Some(tls_config) => (tls_config.cert.as_str(), tls_config.key.as_str()),
+        None => ("./https_cert.pem", "./https_key.pem"),
+    };
+    let names = match proxy.config.tls.as_ref().and_then(|t| t.names.as_ref()) {
+        Some(names) => names
+            .iter()
+            .map(|name| HostName::from_str(name))
+            .collect::<Result<_, _>>()?,
+        None => {
+            let mut names = vec![HostName::Domain("localhost".to_string())];
+            for i in listen.iter().filter(|i| !i.ip().is_unspecified()) {
+                names.push(HostName::from(i.ip()));
+            }
+            names
+        }
+    };
+
+    let (tls_config, fingerprint) =
+        tls::self_signed_server_config(cert, key, &names, &[b"http/1.1"])?;
+    println!("Certificate for https_listen: {cert} (SHA-256: {fingerprint})");
+    let acceptor = TlsAcceptor::from(tls_config);
+
+    for i in listen {
+        let listener = TcpListener::bind(i).await?;
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            loop {
+                let client = match listener.accept().await {
+                    Ok((o, _)) => o,
+                    Err(_) => continue,
+                };
+                if client.set_nodelay(true).is_err() {
+                    continue;
+                }
+
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let client = tokio::time::timeout(tls::ACCEPT_TIMEOUT, acceptor.accept(client))
+                        .await??;
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(client), service_fn(handle))
+                        .with_upgrades()
+                        .await?;
+                    Ok::<_, Error>(())
+                });
+            }
+        });
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+    }
+}
+
+pub async fn handle(request: Request<Incoming>) -> Result<Response<Body>, Error> {
     let request = Body::convert_request(request);
 
+    if let Some(HostName::Domain(domain)) = target_host(&request) {
+        if PROXY.get().ok_or("")?.blocklist.is_blocked(&domain) {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header("connection", "keep-alive")
+                .header("content-type", "text/html; charset=utf-8")
+                .body(Body::new(Full::new(Bytes::from(BLOCKED_HTML))))?);
+        }
+    }
+
     let mut response;
     if request.method() == Method::CONNECT {
         response = connect::run(request).await;
@@ -66,12 +146,32 @@ async fn handle(request: Request<Incoming>) -> Result<Response<Body>, Error> {
     };
 
     if response.is_err() {
-        response = Ok(Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
-            .header("connection", "keep-alive")
-            .header("content-type", "text/html; charset=utf-8")
-            .body(Body::new(Full::new(Bytes::from(ERROR_HTML))))?);
+        response = bad_gateway();
     }
 
     response
 }
+
+fn bad_gateway() -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .header("connection", "keep-alive")
+        .header("content-type", "text/html; charset=utf-8")
+        .body(Body::new(Full::new(Bytes::from(ERROR_HTML))))?)
+}
+
+fn target_host(request: &Request<Body>) -> Option<HostName> {
+    if request.method() == Method::CONNECT {
+        return SocketAddr::from_str(&request.uri().to_string())
+            .ok()
+            .map(|a| a.hostname);
+    }
+
+    match request.uri().host() {
+        Some(host) => HostName::from_str(host).ok(),
+        None => {
+            let host = request.headers().get("host")?.to_str().ok()?;
+            SocketAddr::parse_host_header(host).ok().map(|h| h.0)
+        }
+    }
+}
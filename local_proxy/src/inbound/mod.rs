@@ -1,3 +1,6 @@
 pub mod dns;
+pub mod doh;
+pub mod dot;
 pub mod http;
+pub mod stats;
 pub mod tproxy;
@@ -0,0 +1,59 @@
+use crate::{utils::Body, Error, PROXY};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+pub async fn start() -> Result<(), Error> {
+    let listen = PROXY
+        .get()
+        .unwrap()
+        .config
+        .stats_listen
+        .as_ref()
+        .ok_or("")?;
+    if listen.is_empty() {
+        return Ok(());
+    }
+
+    for i in listen {
+        let listener = TcpListener::bind(i).await?;
+        tokio::spawn(async move {
+            loop {
+                let client = match listener.accept().await {
+                    Ok((o, _)) => TokioIo::new(o),
+                    Err(_) => continue,
+                };
+
+                tokio::spawn(async {
+                    let _ = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(client, service_fn(handle))
+                        .await;
+                });
+            }
+        });
+    }
+
+    loop {
+        tokio::time::sleep(Duration::from_secs(u64::MAX)).await;
+    }
+}
+
+async fn handle(request: Request<Incoming>) -> Result<Response<Body>, Error> {
+    if request.uri().path() != "/stats" {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::new(Full::new(Bytes::new())))?);
+    }
+
+    let stats = PROXY.get().ok_or("")?.query_log.stats()?;
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::new(Full::new(Bytes::from(stats))))?)
+}
@@ -1,12 +1,23 @@
-use crate::{utils::SocketAddr, Error, PROXY};
+use super::http;
+use crate::{
+    outbound::ProxyOutBoundDefaultMethods,
+    utils::{self, HostName, Prefixed, Sniffed, SocketAddr},
+    Error, PROXY,
+};
 
-use std::time::Duration;
+use dns_parser::QueryType;
+use hyper::{body::Incoming, header::HeaderValue, service::service_fn, Request};
+use hyper_util::rt::TokioIo;
+use std::{str::FromStr, time::Duration};
 use tokio::{
-    io::{self, AsyncRead, AsyncWrite},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpListener,
 };
 use tproxy_tokio::{RedirType, TcpListenerRedirExt, TcpStreamRedirExt};
 
+/// A ClientHello with post-quantum key shares spans a few KiB
+const SNIFF_MAX_LEN: usize = 16384;
+
 pub async fn start() -> Result<(), Error> {
     let config = PROXY
         .get()
@@ -50,13 +61,119 @@ async fn run<RW>(mut client: RW, redir_type: RedirType) -> Result<(), Error>
 where
     RW: AsyncRead + AsyncWrite + TcpStreamRedirExt + Unpin + Send + 'static,
 {
-    let addr: SocketAddr = client.destination_addr(redir_type)?.into();
+    let destination = client.destination_addr(redir_type)?;
 
     let proxy = PROXY.get().ok_or("")?;
     let mut proxies = Box::new(proxy.proxy_stack.iter().map(|p| &**p).rev());
-    let mut server_conn = proxies.next().ok_or("")?.connect(proxies, &addr).await?;
+    let config = proxy.config.tproxy_listen.as_ref().ok_or("")?;
+
+    let fake_domain = proxy.fake_ip.lookup(&destination.ip());
+    // Addresses from the pool that are no longer mapped are dropped rather than connected to
+    if fake_domain.is_none() && proxy.fake_ip.contains(&destination.ip()) {
+        return Err("".into());
+    }
+
+    let sniff = config.sniff.unwrap_or(false);
+    let intercept_http = config.intercept_http.unwrap_or(false);
+    let mut sniffed = Vec::new();
+    let mut sniffed_host = None;
+    if (sniff && fake_domain.is_none()) || intercept_http {
+        let timeout = Duration::from_millis(config.sniff_timeout.unwrap_or(300));
+        sniffed_host = sniff_host(&mut client, &mut sniffed, timeout).await?;
+    }
+
+    let is_fake = fake_domain.is_some();
+    let addr: SocketAddr = match (fake_domain, sniffed_host) {
+        (Some(domain), _) => SocketAddr::new(HostName::Domain(domain), destination.port()),
+        (None, Some(hostname)) if sniff && !hostname.is_ipaddr() => {
+            SocketAddr::new(hostname, destination.port())
+        }
+        _ => destination.into(),
+    };
 
+    if intercept_http && utils::is_http_request(&sniffed) {
+        return serve_http(Prefixed::new(sniffed, client), addr).await;
+    }
+
+    // The domain of a fake address is resolved in the family the client chose, by the proxies when there are
+    // any, and here otherwise, as the system resolver may point back at "dns_listen".
+    // Connects directly when `addr` is an IP address.
+    let outermost = proxies.next().ok_or("")?;
+    let direct = proxy.config.proxies.as_ref().is_none_or(|p| p.is_empty());
+    let mut server_conn = if is_fake && direct {
+        let qtype = if destination.is_ipv6() {
+            QueryType::AAAA
+        } else {
+            QueryType::A
+        };
+        let mut result = Err("".into());
+        for ip in addr.hostname.dns_resolve(qtype).await? {
+            let proxies = dyn_clone::clone_box(&*proxies);
+            result = outermost
+                .connect(proxies, &SocketAddr::new(ip, addr.port))
+                .await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        result?
+    } else if is_fake {
+        outermost.connect(proxies, &addr).await?
+    } else {
+        outermost.happy_eyeballs(proxies, &addr).await?
+    };
+
+    server_conn.write_all(&sniffed).await?;
     let _ = io::copy_bidirectional(&mut client, &mut server_conn).await;
 
     Ok(())
 }
+
+/// Serves the client with the HTTP inbound's handler, filling in `Host` from `addr` when missing
+async fn serve_http<RW>(client: RW, addr: SocketAddr) -> Result<(), Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let host = HeaderValue::from_str(&addr.to_string())?;
+    let service = service_fn(move |mut request: Request<Incoming>| {
+        if !request.headers().contains_key("host") {
+            request.headers_mut().insert("host", host.clone());
+        }
+        http::handle(request)
+    });
+
+    hyper::server::conn::http1::Builder::new()
+        .serve_connection(TokioIo::new(client), service)
+        .with_upgrades()
+        .await?;
+
+    Ok(())
+}
+
+/// Reads from `client` into `buf` until a host name is found, giving up after `timeout`
+async fn sniff_host<R>(
+    client: &mut R,
+    buf: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<Option<HostName>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    while buf.len() < SNIFF_MAX_LEN {
+        match utils::sniff(buf) {
+            Sniffed::Host(host) => return Ok(HostName::from_str(&host).ok()),
+            Sniffed::NotFound => return Ok(None),
+            Sniffed::Incomplete => {}
+        }
+
+        buf.reserve(4096);
+        match tokio::time::timeout_at(deadline, client.read_buf(buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(None),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(None)
+}
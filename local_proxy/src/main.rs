@@ -17,21 +17,25 @@ use tokio::{
 use ttl_cache::TtlCache;
 
 static ERROR_HTML: &[u8] = include_bytes!("../static/error.html");
+static BLOCKED_HTML: &[u8] = include_bytes!("../static/blocked.html");
 static PROXY: OnceCell<ProxyState> = OnceCell::new();
 type Error = Box<dyn std::error::Error + Sync + Send>;
 type Connection = Box<dyn Stream + Unpin + Send>;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), Error> {
     let stdin = std::io::stdin();
     let mut stdout = std::io::stdout();
 
     let mut config = String::new();
     std::fs::File::open("./config.json5")
-        .unwrap()
-        .read_to_string(&mut config)
-        .unwrap();
-    let mut config: Config = json5::from_str(&config).unwrap();
+        .and_then(|mut f| f.read_to_string(&mut config))
+        .map_err(|e| format!("config.json5: {e}"))?;
+    let mut config: Config = json5::from_str(&config).map_err(|e| format!("config.json5: {e}"))?;
+    let tls_connectors = outbound::layer::TlsConnectors::new(config.client_hello.as_ref())
+        .map_err(|e| format!("client_hello: {e}"))?;
+    let fragment_layers = outbound::FragmentLayers::new(config.fragment_options.as_ref())
+        .map_err(|e| format!("fragment_options: {e}"))?;
 
     let mut proxy_stack: Vec<Box<dyn ProxyOutBound>> = vec![Box::new(outbound::Raw::new())];
     if let Some(proxies) = &mut config.proxies {
@@ -74,36 +78,87 @@ async fn main() {
             let proxy_protocol: Vec<&str> = proxy.protocol.split('+').collect();
             for layer in &proxy_protocol[0..proxy_protocol.len() - 1] {
                 match *layer {
-                    "tls" => proxy_stack.push(Box::new(outbound::layer::TlsClient {})),
-                    _ => panic!("This protocol can not use: {}", layer),
+                    "tls" => proxy_stack.push(Box::new(
+                        outbound::layer::TlsClient::from_config(
+                            proxy.tls.as_ref(),
+                            &tls_connectors,
+                        )
+                        .map_err(|e| format!("{}: {e}", proxy.server))?,
+                    )),
+                    _ => return Err(format!("This protocol can not use: {}", layer).into()),
                 }
             }
 
             let proxy_protocol_main = proxy_protocol[proxy_protocol.len() - 1];
             match proxy_protocol_main {
-                "http" => proxy_stack.push(Box::new(outbound::HttpProxy::new(proxy).unwrap())),
-                "socks4" => proxy_stack.push(Box::new(outbound::Socks4Proxy::new(proxy).unwrap())),
-                "socks5" => proxy_stack.push(Box::new(outbound::Socks5Proxy::new(proxy).unwrap())),
-                _ => panic!("This protocol can not use: {}", proxy_protocol_main),
+                "http" => proxy_stack.push(Box::new(outbound::HttpProxy::new(proxy)?)),
+                "socks4" => proxy_stack.push(Box::new(outbound::Socks4Proxy::new(proxy)?)),
+                "socks5" => proxy_stack.push(Box::new(outbound::Socks5Proxy::new(proxy)?)),
+                _ => {
+                    return Err(
+                        format!("This protocol can not use: {}", proxy_protocol_main).into(),
+                    )
+                }
             }
         }
     }
 
+    // Below "Fragment", which hands plain HTTP requests down the stack
+    if let Some(http_obfuscation) = &config.http_obfuscation {
+        proxy_stack.push(Box::new(outbound::layer::HttpObfuscation::new(
+            http_obfuscation,
+        )));
+    }
+
     if let Some(2..) | None = config.fragment {
-        proxy_stack.push(Box::new(outbound::layer::Fragment::new()));
+        proxy_stack.push(Box::new(fragment_layers.configured().clone()));
+    }
+    let splits = config.dns.as_ref().and_then(|d| d.split.as_ref());
+    for upstream in config
+        .doh
+        .iter()
+        .chain(splits.into_iter().flatten().map(|s| &s.upstream))
+    {
+        // QUIC would leave the proxies out
+        let has_proxies = config.proxies.as_ref().is_some_and(|p| !p.is_empty());
+        let endpoint = &upstream.endpoint;
+        if has_proxies && (endpoint.starts_with("quic://") || endpoint.starts_with("h3://")) {
+            return Err(format!("This endpoint can not be used with proxies: {}", endpoint).into());
+        }
     }
 
-    let dns_cache = if config.doh.is_some() {
+    let dns_cache = if config.has_upstream() {
         TtlCache::new(65535)
     } else {
         TtlCache::new(0)
     };
 
+    let hosts = utils::Hosts::new(config.dns.as_ref()).map_err(|e| format!("dns.hosts: {e}"))?;
+    let blocklist =
+        utils::Blocklist::new(config.dns.as_ref()).map_err(|e| format!("dns.blocklists: {e}"))?;
+    let ecs = utils::Ecs::new(config.dns.as_ref()).map_err(|e| format!("dns.ecs: {e}"))?;
+    let fake_ip =
+        utils::FakeIp::new(config.dns.as_ref()).map_err(|e| format!("dns.fake_ip: {e}"))?;
+    let fronting =
+        utils::Fronting::new(config.fronting.as_ref()).map_err(|e| format!("fronting: {e}"))?;
+    let query_log =
+        utils::QueryLog::new(config.dns.as_ref()).map_err(|e| format!("dns.query_log: {e}"))?;
+    let mitm = inbound::http::Mitm::new(config.mitm.as_ref()).map_err(|e| format!("mitm: {e}"))?;
+
     if PROXY
         .set(ProxyState {
             config,
             dns_cache: RwLock::new(dns_cache),
+            hosts,
+            blocklist,
+            ecs,
+            fake_ip,
+            fronting,
+            query_log,
+            mitm,
             proxy_stack,
+            fragment_layers,
+            tls_connectors,
         })
         .is_err()
     {
@@ -112,19 +167,34 @@ async fn main() {
 
     let _ = tokio::join!(
         inbound::http::start(),
+        inbound::http::start_tls(),
         inbound::tproxy::start(),
         inbound::dns::start(),
+        inbound::doh::start(),
+        inbound::dot::start(),
+        inbound::stats::start(),
         async {
             println!("Server started");
         }
     );
+
+    Ok(())
 }
 
 #[allow(clippy::type_complexity)]
 struct ProxyState {
     config: Config,
     dns_cache: RwLock<TtlCache<Vec<u8>, Vec<u8>>>,
+    hosts: utils::Hosts,
+    blocklist: utils::Blocklist,
+    ecs: utils::Ecs,
+    fake_ip: utils::FakeIp,
+    fronting: utils::Fronting,
+    query_log: utils::QueryLog,
+    mitm: inbound::http::Mitm,
     proxy_stack: Vec<Box<dyn ProxyOutBound>>,
+    fragment_layers: outbound::FragmentLayers,
+    tls_connectors: outbound::layer::TlsConnectors,
 }
 
 pub trait Stream: AsyncRead + AsyncWrite {}
@@ -0,0 +1,95 @@
+//! Cipher suites, groups and GREASE ECH offered in TLS ClientHellos.
+//!
+//! rustls exposes no control over extension order, padding or GREASE values, so this narrows
+//! what is offered rather than imitating a browser's fingerprint.
+
+use crate::{config::ClientHelloConfig, Error};
+
+use ::aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use std::sync::Arc;
+use tokio_rustls::rustls::{
+    client::{EchConfig, EchGreaseConfig, EchMode},
+    crypto::{aws_lc_rs, hpke::HpkePublicKey, CryptoProvider},
+    ClientConfig, ConfigBuilder, SupportedProtocolVersion, WantsVerifier,
+};
+
+#[derive(Clone)]
+pub struct ClientHello {
+    provider: Arc<CryptoProvider>,
+    grease_ech: bool,
+}
+
+impl ClientHello {
+    pub fn new(config: Option<&ClientHelloConfig>) -> Result<Self, Error> {
+        let mut provider = aws_lc_rs::default_provider();
+        let config = match config {
+            Some(config) => config,
+            None => {
+                return Ok(Self {
+                    provider: Arc::new(provider),
+                    grease_ech: false,
+                })
+            }
+        };
+
+        if let Some(names) = &config.cipher_suites {
+            provider.cipher_suites = names
+                .iter()
+                .map(|name| {
+                    aws_lc_rs::ALL_CIPHER_SUITES
+                        .iter()
+                        .find(|s| s.suite().as_str() == Some(name.as_str()))
+                        .copied()
+                        .ok_or("")
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if let Some(names) = &config.groups {
+            provider.kx_groups = names
+                .iter()
+                .map(|name| {
+                    aws_lc_rs::ALL_KX_GROUPS
+                        .iter()
+                        .find(|g| g.name().as_str() == Some(name.as_str()))
+                        .copied()
+                        .ok_or("")
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        Ok(Self {
+            provider: Arc::new(provider),
+            grease_ech: config.grease_ech.unwrap_or(false),
+        })
+    }
+
+    /// GREASE ECH needs TLS 1.3, so it can not be offered alongside TLS 1.2
+    pub fn grease_ech(&self) -> bool {
+        self.grease_ech
+    }
+
+    /// Sends `ech` when it is given, and GREASE ECH when it is enabled instead. Both of them need TLS 1.3.
+    pub fn builder(
+        &self,
+        versions: &[&'static SupportedProtocolVersion],
+        ech: Option<&EchConfig>,
+    ) -> Result<ConfigBuilder<ClientConfig, WantsVerifier>, Error> {
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&self.provider));
+        let mode = match ech {
+            Some(ech) => EchMode::Enable(ech.clone()),
+            None if self.grease_ech => {
+                // Any X25519 public key will do, as the server can not decrypt it anyway
+                let mut placeholder_key = vec![0; 32];
+                SystemRandom::new().fill(&mut placeholder_key)?;
+                EchMode::Grease(EchGreaseConfig::new(
+                    aws_lc_rs::hpke::DH_KEM_X25519_HKDF_SHA256_AES_128,
+                    HpkePublicKey(placeholder_key),
+                ))
+            }
+            None => return Ok(builder.with_protocol_versions(versions)?),
+        };
+
+        Ok(builder.with_ech(mode)?)
+    }
+}
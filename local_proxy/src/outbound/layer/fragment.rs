@@ -1,32 +1,254 @@
 use super::Layer;
-use crate::{utils::SocketAddr, Connection, Error};
+use crate::{
+    config::FragmentConfig,
+    inbound::http::http_proxy::RequestConfig,
+    outbound::proxy_stack,
+    utils::{sni_range, SocketAddr, SuffixMatcher},
+    Connection, Error,
+};
 
 use async_trait::async_trait;
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     future::Future,
-    io::ErrorKind,
+    io::{self, ErrorKind},
     mem,
     pin::{pin, Pin},
+    str::FromStr,
+    sync::{Arc, Mutex},
     task::{ready, Poll},
     time::Duration,
 };
-use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    time::Sleep,
+};
+use ttl_cache::TtlCache;
+
+/// Hosts where a fragmented handshake failed, connected to without fragmentation until expiry
+static FALLBACKS: Lazy<Mutex<TtlCache<String, ()>>> = Lazy::new(|| Mutex::new(TtlCache::new(4096)));
+
+/// Bytes kept for replaying after a fallback, more than a ClientHello with early data needs
+const MAX_REPLAY_LEN: usize = 65536;
+
+#[derive(Clone)]
+pub struct Fragment {
+    mode: FragmentMode,
+    /// Set by `with_mode`, overriding `Policy::modes`
+    fixed_mode: bool,
+    strategy: Strategy,
+    delay: Duration,
+    policy: Arc<Policy>,
+    auto: bool,
+    auto_timeout: Duration,
+    auto_ttl: Duration,
+}
 
-pub struct Fragment();
+/// Hosts to fragment connections to
+struct Policy {
+    include: Option<SuffixMatcher>,
+    exclude: SuffixMatcher,
+    modes: SuffixMatcher<FragmentMode>,
+}
+
+/// How the pieces of the ClientHello are sent
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FragmentMode {
+    /// Split into TLS records, sent together unless there is a delay
+    Record,
+    /// Kept as one TLS record, written across TCP segments
+    Segment,
+    /// Split into TLS records, each in its own TCP segment
+    Both,
+}
+
+impl FromStr for FragmentMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(Self::Record),
+            "segment" => Ok(Self::Segment),
+            "both" => Ok(Self::Both),
+            _ => Err("".into()),
+        }
+    }
+}
+
+/// Where the ClientHello is split into records
+#[derive(Clone, Copy)]
+enum Strategy {
+    /// Records of this many bytes
+    Fixed(usize),
+    /// Records with random sizes in this range
+    Random(usize, usize),
+    /// Records split at the start, middle and end of the server name
+    Sni,
+    /// This many records of about equal size
+    Count(usize),
+}
 
 impl Fragment {
-    pub fn new() -> Self {
-        Self()
+    pub fn new(config: Option<&FragmentConfig>) -> Result<Self, Error> {
+        let config = match config {
+            Some(c) => c,
+            None => {
+                return Ok(Self {
+                    mode: FragmentMode::Both,
+                    fixed_mode: false,
+                    strategy: Strategy::Fixed(1),
+                    delay: Duration::ZERO,
+                    policy: Arc::new(Policy {
+                        include: None,
+                        exclude: SuffixMatcher::default(),
+                        modes: SuffixMatcher::default(),
+                    }),
+                    auto: false,
+                    auto_timeout: Duration::ZERO,
+                    auto_ttl: Duration::ZERO,
+                })
+            }
+        };
+
+        let strategy = match config.strategy.as_deref() {
+            None | Some("fixed") => Strategy::Fixed(config.size.unwrap_or(1)),
+            Some("random") => {
+                let min = config.min_size.unwrap_or(1);
+                Strategy::Random(min, config.max_size.unwrap_or(min.max(16)))
+            }
+            Some("sni") => Strategy::Sni,
+            Some("count") => Strategy::Count(config.count.unwrap_or(2)),
+            Some(_) => return Err("".into()),
+        };
+        match strategy {
+            Strategy::Fixed(0) | Strategy::Random(0, _) | Strategy::Count(0) => {
+                return Err("".into())
+            }
+            Strategy::Random(min, max) if min > max => return Err("".into()),
+            _ => {}
+        }
+
+        let modes = config
+            .modes
+            .iter()
+            .flatten()
+            .map(|(domain, mode)| Ok((domain, mode.parse()?)))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Self {
+            mode: match &config.mode {
+                Some(m) => m.parse()?,
+                None => FragmentMode::Both,
+            },
+            fixed_mode: false,
+            strategy,
+            delay: Duration::from_millis(config.delay.unwrap_or(0)),
+            policy: Arc::new(Policy {
+                include: config.include.as_ref().map(SuffixMatcher::from_domains),
+                exclude: SuffixMatcher::from_domains(config.exclude.iter().flatten()),
+                modes: SuffixMatcher::new(modes),
+            }),
+            auto: config.auto.unwrap_or(false),
+            auto_timeout: Duration::from_millis(config.auto_timeout.unwrap_or(3000)),
+            auto_ttl: Duration::from_secs(config.auto_ttl.unwrap_or(3600)),
+        })
+    }
+
+    pub fn with_mode(mut self, mode: FragmentMode) -> Self {
+        self.mode = mode;
+        self.fixed_mode = true;
+        self
+    }
+}
+
+impl Policy {
+    /// Whether connections to `host` are fragmented
+    fn applies_to(&self, host: &str) -> bool {
+        if FALLBACKS.lock().is_ok_and(|f| f.get(host).is_some()) {
+            return false;
+        }
+
+        !self.exclude.matches(host) && self.include.as_ref().is_none_or(|i| i.matches(host))
+    }
+
+    /// The mode set for the longest matching domain in `modes`
+    fn mode_for(&self, host: &str) -> Option<FragmentMode> {
+        self.modes.get(host).copied()
+    }
+}
+
+impl Strategy {
+    /// End offsets of the records `payload` is split into
+    fn record_ends(&self, payload: &[u8]) -> Vec<usize> {
+        let len = payload.len();
+        let mut ends: Vec<usize> = match *self {
+            Strategy::Fixed(size) => (size..len).step_by(size).collect(),
+            Strategy::Random(min, max) => {
+                let rng = SystemRandom::new();
+                let mut ends = Vec::new();
+                let mut end = 0;
+                loop {
+                    let mut random = [0; 4];
+                    let _ = rng.fill(&mut random);
+                    end += min + u32::from_ne_bytes(random) as usize % (max - min + 1);
+                    if end >= len {
+                        break ends;
+                    }
+                    ends.push(end);
+                }
+            }
+            Strategy::Sni => match sni_range(payload) {
+                Some(Some(name)) => [name.start, name.start + name.len() / 2, name.end]
+                    .into_iter()
+                    .collect(),
+                _ => return Strategy::Count(2).record_ends(payload),
+            },
+            Strategy::Count(count) => (1..count).map(|i| len * i / count).collect(),
+        };
+
+        ends.retain(|e| (1..len).contains(e));
+        ends.dedup();
+        if len > 0 {
+            ends.push(len);
+        }
+        ends
     }
 }
 
 #[async_trait]
 impl Layer for Fragment {
-    async fn wrap<RW>(&self, stream: RW, _: &SocketAddr) -> Result<Connection, Error>
+    async fn wrap<RW>(&self, stream: RW, addr: &SocketAddr) -> Result<Connection, Error>
     where
         RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        Ok(Box::new(FragmentLayer::new(stream)))
+        // Resolved connections only carry an address, so the server name is preferred
+        let host = addr.hostname.to_string().to_ascii_lowercase();
+        let stream = FragmentLayer::new(
+            stream,
+            self.mode,
+            self.fixed_mode,
+            self.strategy,
+            self.delay,
+            self.policy.clone(),
+            host.clone(),
+        );
+        if !self.auto {
+            return Ok(Box::new(stream));
+        }
+        Ok(Box::new(Fallback {
+            inner: Box::new(stream),
+            addr: addr.clone(),
+            policy: self.policy.clone(),
+            host,
+            timeout: self.auto_timeout,
+            ttl: self.auto_ttl,
+            sent: Vec::new(),
+            state: FallbackState::Watching { timer: None },
+        }))
     }
 
     fn is_http_passthrough(&self) -> bool {
@@ -41,19 +263,79 @@ where
     inner: RW,
     state: State,
     timer: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    mode: FragmentMode,
+    fixed_mode: bool,
+    strategy: Strategy,
+    delay: Duration,
+    delay_timer: Option<Pin<Box<Sleep>>>,
+    policy: Arc<Policy>,
+    host: String,
 }
 
 impl<RW> FragmentLayer<RW>
 where
     RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    fn new(stream: RW) -> Self {
+    fn new(
+        stream: RW,
+        mode: FragmentMode,
+        fixed_mode: bool,
+        strategy: Strategy,
+        delay: Duration,
+        policy: Arc<Policy>,
+        host: String,
+    ) -> Self {
         Self {
             inner: stream,
             state: State::WaitingHeader { buf: Buffer::new() },
             timer: None,
+            mode,
+            fixed_mode,
+            strategy,
+            delay,
+            delay_timer: None,
+            policy,
+            host,
         }
     }
+
+    /// Splits `record` for `host` into the buffers written one after another
+    fn writes(&self, record: &[u8], header: &Header, host: &str) -> VecDeque<Buffer> {
+        let mode = match self.policy.mode_for(host) {
+            Some(mode) if !self.fixed_mode => mode,
+            _ => self.mode,
+        };
+        let payload = &record[5..];
+        let mut start = 0;
+        let mut writes: VecDeque<Vec<u8>> = self
+            .strategy
+            .record_ends(payload)
+            .into_iter()
+            .map(|end| {
+                let write = match mode {
+                    FragmentMode::Segment if start == 0 => record[..(5 + end)].to_vec(),
+                    FragmentMode::Segment => record[(5 + start)..(5 + end)].to_vec(),
+                    FragmentMode::Record | FragmentMode::Both => {
+                        let msg = &payload[start..end];
+                        let mut write = header.base.to_vec();
+                        write.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+                        write.extend_from_slice(msg);
+                        write
+                    }
+                };
+                start = end;
+                write
+            })
+            .collect();
+
+        if mode == FragmentMode::Record && self.delay.is_zero() {
+            writes = VecDeque::from([writes.into_iter().flatten().collect()]);
+        }
+        writes
+            .into_iter()
+            .map(|inner| Buffer { inner, ptr: 0 })
+            .collect()
+    }
 }
 
 impl<RW> AsyncRead for FragmentLayer<RW>
@@ -140,12 +422,18 @@ where
             if buf.inner.len() >= header.len {
                 let mut buf_ = Buffer::new();
                 mem::swap(buf, &mut buf_);
-
-                self.state = State::SendingMessage {
-                    buf: buf_,
-                    header: *header,
-                    chunk: None,
-                };
+                buf_.ptr = header.len;
+
+                let header = *header;
+                let record = &buf_.inner[..(header.len)];
+                let host = server_name(record).unwrap_or_else(|| self.host.clone());
+                if self.policy.applies_to(&host) {
+                    let writes = self.writes(record, &header, &host);
+                    self.state = State::SendingMessage { buf: buf_, writes };
+                } else {
+                    buf_.ptr = 0;
+                    self.state = State::SendingRawBuffer { buf: buf_ };
+                }
             }
         }
 
@@ -156,41 +444,36 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<Result<(), std::io::Error>> {
-        let Self { inner, state, .. } = &mut *self;
-
-        if let State::SendingMessage { buf, header, chunk } = state {
-            let msg_max_size = 1_u16;
-            while header.len > buf.ptr {
-                if let Some(chunk) = chunk {
-                    while chunk.inner.len() > chunk.ptr {
-                        ready!(pin!(&mut *inner).poll_flush(cx))?;
-
-                        let send = &chunk.inner[(chunk.ptr)..(chunk.ptr + 1)];
-                        let written = ready!(pin!(&mut *inner).poll_write(cx, send)?);
-                        if written == 0 {
-                            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
-                        }
+        let Self {
+            inner,
+            state,
+            delay,
+            delay_timer,
+            ..
+        } = &mut *self;
+
+        if let State::SendingMessage { buf, writes } = state {
+            while let Some(write) = writes.front_mut() {
+                if let Some(timer) = delay_timer {
+                    ready!(timer.as_mut().poll(cx));
+                    *delay_timer = None;
+                }
 
-                        chunk.ptr += written;
+                while write.inner.len() > write.ptr {
+                    let send = &write.inner[(write.ptr)..];
+                    let written = ready!(pin!(&mut *inner).poll_write(cx, send)?);
+                    if written == 0 {
+                        return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
                     }
 
-                    buf.ptr += chunk.inner.len() - (header.base.len() + 2);
+                    write.ptr += written;
                 }
+                ready!(pin!(&mut *inner).poll_flush(cx))?;
 
-                let mut chunk_ = Buffer::new();
-                chunk_.inner.extend_from_slice(&header.base);
-
-                let mut end = buf.ptr + msg_max_size as usize;
-                if end > header.len {
-                    end = header.len;
+                writes.pop_front();
+                if !delay.is_zero() && !writes.is_empty() {
+                    *delay_timer = Some(Box::pin(tokio::time::sleep(*delay)));
                 }
-
-                let msg = &buf.inner[(buf.ptr)..end];
-                let msg_len = (msg.len() as u16).to_be_bytes();
-                chunk_.inner.extend_from_slice(&msg_len);
-                chunk_.inner.extend_from_slice(msg);
-
-                *chunk = Some(chunk_)
             }
 
             let mut buf_ = Buffer::new();
@@ -201,7 +484,7 @@ where
 
         if let State::SendingRawBuffer { buf } = state {
             while buf.inner.len() > buf.ptr {
-                let wrote = ready!(pin!(&mut *inner).poll_write(cx, &buf.inner))?;
+                let wrote = ready!(pin!(&mut *inner).poll_write(cx, &buf.inner[(buf.ptr)..]))?;
                 buf.ptr += wrote;
             }
 
@@ -219,6 +502,193 @@ where
     }
 }
 
+/// Retries a fragmented handshake once without fragmentation when the server
+/// closes or resets the connection, sends an alert before anything else, or does not answer in
+/// time
+struct Fallback {
+    inner: Connection,
+    addr: SocketAddr,
+    policy: Arc<Policy>,
+    host: String,
+    timeout: Duration,
+    ttl: Duration,
+    /// Everything written before the server's first byte
+    sent: Vec<u8>,
+    state: FallbackState,
+}
+
+enum FallbackState {
+    /// `timer` bounds the wait for the server's first byte
+    Watching {
+        timer: Option<Pin<Box<Sleep>>>,
+    },
+    Reconnecting(Pin<Box<dyn Future<Output = Result<Connection, Error>> + Send>>),
+    Replaying {
+        buf: Buffer,
+    },
+    Done,
+}
+
+impl Fallback {
+    fn fall_back(&mut self, host: String) {
+        if let Ok(mut fallbacks) = FALLBACKS.lock() {
+            fallbacks.insert(host, (), self.ttl);
+        }
+
+        // The fragment layer is always outermost and the only one chosen by the request's
+        // RequestConfig, whose fake host and resolution are already applied to `addr`.
+        // So this is the stack the original connection went through, less this layer.
+        let addr = self.addr.clone();
+        self.state = FallbackState::Reconnecting(Box::pin(async move {
+            let mut req_conf = RequestConfig::new();
+            req_conf.fragment = Some(false);
+
+            let mut proxies = proxy_stack(&req_conf)?;
+            proxies.next().ok_or("")?.connect(proxies, &addr).await
+        }));
+    }
+
+    /// Drives a reconnection in progress
+    fn poll_fallback(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                FallbackState::Reconnecting(connect) => {
+                    self.inner = ready!(connect.as_mut().poll(cx)).map_err(io::Error::other)?;
+                    self.state = FallbackState::Replaying {
+                        buf: Buffer {
+                            inner: mem::take(&mut self.sent),
+                            ptr: 0,
+                        },
+                    };
+                }
+                FallbackState::Replaying { buf } => {
+                    while buf.inner.len() > buf.ptr {
+                        let send = &buf.inner[(buf.ptr)..];
+                        let written = ready!(pin!(&mut self.inner).poll_write(cx, send))?;
+                        if written == 0 {
+                            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+                        }
+
+                        buf.ptr += written;
+                    }
+                    ready!(pin!(&mut self.inner).poll_flush(cx))?;
+
+                    self.state = FallbackState::Done;
+                }
+                FallbackState::Watching { .. } | FallbackState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncRead for Fallback {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_fallback(cx))?;
+
+        // Only a fragmented ClientHello is worth retrying, and only until the server answers
+        let host = server_name(&self.sent).unwrap_or_else(|| self.host.clone());
+        let is_fragmented = self.sent.first() == Some(&0x16)
+            && self.sent.get(5) == Some(&0x01)
+            && self.policy.applies_to(&host);
+        let timeout = self.timeout;
+        let Self {
+            inner, state, sent, ..
+        } = &mut *self;
+        let timer = match state {
+            FallbackState::Watching { .. } if sent.is_empty() => {
+                let filled = buf.filled().len();
+                let result = ready!(pin!(inner).poll_read(cx, buf));
+                if buf.filled().len() > filled {
+                    *state = FallbackState::Done;
+                }
+                return Poll::Ready(result);
+            }
+            FallbackState::Watching { timer } if is_fragmented => timer,
+            FallbackState::Watching { .. } => {
+                *state = FallbackState::Done;
+                *sent = Vec::new();
+                return pin!(inner).poll_read(cx, buf);
+            }
+            _ => return pin!(inner).poll_read(cx, buf),
+        };
+
+        let filled = buf.filled().len();
+        match pin!(&mut *inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                let rejected = match &result {
+                    // An alert means the server rejected the handshake
+                    Ok(()) if buf.filled().len() > filled => buf.filled()[filled] == 0x15,
+                    Ok(()) => true,
+                    Err(e) => e.kind() == ErrorKind::ConnectionReset,
+                };
+                if !rejected {
+                    *state = FallbackState::Done;
+                    *sent = Vec::new();
+                    return Poll::Ready(result);
+                }
+                buf.set_filled(filled);
+            }
+            // A server that drops the handshake silently never answers
+            Poll::Pending => {
+                let timer = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                ready!(timer.as_mut().poll(cx));
+            }
+        }
+
+        self.fall_back(host);
+        ready!(self.poll_fallback(cx))?;
+        pin!(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Fallback {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        ready!(self.poll_fallback(cx))?;
+
+        let written = ready!(pin!(&mut self.inner).poll_write(cx, buf))?;
+        if let FallbackState::Watching { .. } = self.state {
+            if self.sent.len() + written > MAX_REPLAY_LEN {
+                self.state = FallbackState::Done;
+                self.sent = Vec::new();
+            } else {
+                self.sent.extend_from_slice(&buf[..written]);
+            }
+        }
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        ready!(self.poll_fallback(cx))?;
+        pin!(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        pin!(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Server name in `record` if it starts with a ClientHello
+fn server_name(record: &[u8]) -> Option<String> {
+    let handshake = record.get(5..)?;
+    let name = &handshake[sni_range(handshake)??];
+    Some(std::str::from_utf8(name).ok()?.to_ascii_lowercase())
+}
+
 enum State {
     WaitingHeader {
         buf: Buffer,
@@ -229,8 +699,7 @@ enum State {
     },
     SendingMessage {
         buf: Buffer,
-        header: Header,
-        chunk: Option<Buffer>,
+        writes: VecDeque<Buffer>,
     },
     SendingRawBuffer {
         buf: Buffer,
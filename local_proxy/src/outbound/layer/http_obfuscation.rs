@@ -0,0 +1,230 @@
+//! Rewrites the head of plain HTTP requests so that keyword filters on the `Host` header miss it.
+//! Anything that does not start with an HTTP/1 request line is passed through.
+
+use super::Layer;
+use crate::{config::HttpObfuscationConfig, utils::SocketAddr, Connection, Error};
+
+use async_trait::async_trait;
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    mem,
+    pin::{pin, Pin},
+    task::{ready, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Request heads are not buffered beyond this
+const MAX_HEAD_LEN: usize = 65536;
+
+#[derive(Clone, Copy)]
+pub struct HttpObfuscation {
+    host_case: bool,
+    extra_space: bool,
+    remove_space: bool,
+    split: bool,
+}
+
+impl HttpObfuscation {
+    pub fn new(config: &HttpObfuscationConfig) -> Self {
+        Self {
+            host_case: config.host_case.unwrap_or(true),
+            extra_space: config.extra_space.unwrap_or(false),
+            remove_space: config.remove_space.unwrap_or(false),
+            split: config.split.unwrap_or(true),
+        }
+    }
+
+    /// Rewrites a request head, split into the buffers written one after another
+    fn obfuscate(&self, head: &[u8]) -> VecDeque<Vec<u8>> {
+        let mut rewritten = Vec::new();
+        let mut splits = Vec::new();
+
+        let mut lines = head.split_inclusive(|b| *b == b'\n');
+        if let Some(request_line) = lines.next() {
+            // Inside the method, so that neither piece holds a whole request line
+            splits.push(request_line.len().min(2));
+            rewritten.extend_from_slice(request_line);
+        }
+
+        for line in lines {
+            let colon = match line.iter().position(|b| *b == b':') {
+                Some(i) if line[..i].eq_ignore_ascii_case(b"host") => i,
+                _ => {
+                    rewritten.extend_from_slice(line);
+                    continue;
+                }
+            };
+            let value = line[(colon + 1)..].trim_ascii();
+
+            rewritten.extend_from_slice(if self.host_case {
+                b"hOsT"
+            } else {
+                &line[..colon]
+            });
+            rewritten.extend_from_slice(if self.remove_space { b":" } else { b": " });
+            splits.push(rewritten.len() + value.len() / 2);
+            rewritten.extend_from_slice(value);
+            if self.extra_space {
+                rewritten.push(b' ');
+            }
+            rewritten.extend_from_slice(b"\r\n");
+        }
+
+        if !self.split {
+            return VecDeque::from([rewritten]);
+        }
+        let mut writes = VecDeque::new();
+        let mut start = 0;
+        for end in splits.into_iter().chain([rewritten.len()]) {
+            if end > start {
+                writes.push_back(rewritten[start..end].to_vec());
+                start = end;
+            }
+        }
+        writes
+    }
+}
+
+#[async_trait]
+impl Layer for HttpObfuscation {
+    async fn wrap<RW>(&self, stream: RW, _: &SocketAddr) -> Result<Connection, Error>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        Ok(Box::new(HttpObfuscationLayer {
+            inner: stream,
+            config: *self,
+            state: State::WaitingHead { buf: Vec::new() },
+        }))
+    }
+}
+
+struct HttpObfuscationLayer<RW> {
+    inner: RW,
+    config: HttpObfuscation,
+    state: State,
+}
+
+enum State {
+    WaitingHead { buf: Vec<u8> },
+    Sending { writes: VecDeque<Buffer> },
+    SendingData,
+}
+
+struct Buffer {
+    inner: Vec<u8>,
+    ptr: usize,
+}
+
+impl<RW> AsyncRead for HttpObfuscationLayer<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let State::Sending { .. } = self.state {
+            let _ = self.as_mut().poll_flush(cx)?;
+        }
+
+        pin!(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<RW> AsyncWrite for HttpObfuscationLayer<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf_write: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if let State::Sending { .. } = self.state {
+            ready!(self.as_mut().poll_flush(cx))?;
+        }
+
+        let config = self.config;
+        let buf = match &mut self.state {
+            State::WaitingHead { buf } => buf,
+            _ => return pin!(&mut self.inner).poll_write(cx, buf_write),
+        };
+        buf.extend_from_slice(buf_write);
+
+        let method_len = buf.iter().take_while(|b| b.is_ascii_uppercase()).count();
+        let is_http = match buf.get(method_len) {
+            None => method_len < 16,
+            Some(b) => *b == b' ' && method_len > 0,
+        };
+        let head_len = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4);
+
+        let writes = match head_len {
+            _ if !is_http || buf.len() > MAX_HEAD_LEN => VecDeque::from([mem::take(buf)]),
+            Some(len) => {
+                let body = buf.split_off(len);
+                let mut writes = config.obfuscate(buf);
+                writes.push_back(body);
+                writes
+            }
+            None => return Poll::Ready(Ok(buf_write.len())),
+        };
+        self.state = State::Sending {
+            writes: writes
+                .into_iter()
+                .filter(|w| !w.is_empty())
+                .map(|inner| Buffer { inner, ptr: 0 })
+                .collect(),
+        };
+
+        Poll::Ready(Ok(buf_write.len()))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        let Self { inner, state, .. } = &mut *self;
+
+        // A partial head is sent as it is, as the writer waits for it
+        if let State::WaitingHead { buf } = state {
+            if buf.is_empty() {
+                return pin!(&mut *inner).poll_flush(cx);
+            }
+            let buf = mem::take(buf);
+            *state = State::Sending {
+                writes: VecDeque::from([Buffer { inner: buf, ptr: 0 }]),
+            };
+        }
+
+        if let State::Sending { writes } = state {
+            while let Some(write) = writes.front_mut() {
+                while write.inner.len() > write.ptr {
+                    let send = &write.inner[(write.ptr)..];
+                    let written = ready!(pin!(&mut *inner).poll_write(cx, send)?);
+                    if written == 0 {
+                        return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+                    }
+
+                    write.ptr += written;
+                }
+                ready!(pin!(&mut *inner).poll_flush(cx))?;
+
+                writes.pop_front();
+            }
+
+            *state = State::SendingData;
+        }
+
+        pin!(&mut *inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        pin!(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,8 +1,11 @@
+mod client_hello;
 mod fragment;
+mod http_obfuscation;
 mod tls;
 
-pub use fragment::Fragment;
-pub use tls::TlsClient;
+pub use fragment::{Fragment, FragmentMode};
+pub use http_obfuscation::HttpObfuscation;
+pub use tls::{TlsClient, TlsConnectors};
 
 use super::{ProxyOutBound, ProxyOutBoundDefaultMethods};
 use crate::{
@@ -1,40 +1,422 @@
-use super::Layer;
-use crate::{utils::SocketAddr, Connection, Error};
+use super::{client_hello::ClientHello, Layer};
+use crate::{
+    config::{ClientHelloConfig, TlsClientConfig},
+    utils::{ech_config_list, SocketAddr},
+    Connection, Error, PROXY,
+};
 
 use async_trait::async_trait;
+use base64::Engine;
 use once_cell::sync::Lazy;
-use std::sync::Arc;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::{rustls, TlsConnector};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::{
+            danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            EchConfig, WebPkiServerVerifier,
+        },
+        crypto::aws_lc_rs::hpke::ALL_SUPPORTED_SUITES,
+        pki_types::{
+            pem::PemObject, CertificateDer, EchConfigListBytes, PrivateKeyDer, ServerName, UnixTime,
+        },
+        server::ParsedCertificate,
+        CertificateError, DigitallySignedStruct, PeerIncompatible, SignatureScheme,
+    },
+    TlsConnector,
+};
 
-static CONNECTOR: Lazy<TlsConnector> = Lazy::new(|| {
+pub static ROOT_CERTS: Lazy<Arc<rustls::RootCertStore>> = Lazy::new(|| {
     let mut certs = rustls::RootCertStore::empty();
     for cert in rustls_native_certs::load_native_certs().unwrap() {
         let _ = certs.add(cert);
     }
 
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(certs)
-        .with_no_client_auth();
-
-    TlsConnector::from(Arc::new(config))
+    Arc::new(certs)
 });
 
-pub struct TlsClient();
+/// Connectors shaped by the global "client_hello", for https:// requests, "doh" endpoints,
+/// and "tls" layers without their own options
+pub struct TlsConnectors {
+    client_hello: ClientHello,
+    http1: TlsConnector,
+    h2: TlsConnector,
+}
+
+impl TlsConnectors {
+    pub fn new(config: Option<&ClientHelloConfig>) -> Result<Self, Error> {
+        let client_hello = ClientHello::new(config)?;
+        let connector = |alpn_protocols| -> Result<TlsConnector, Error> {
+            let mut config = client_hello
+                .builder(rustls::DEFAULT_VERSIONS, None)?
+                .with_root_certificates(Arc::clone(&ROOT_CERTS))
+                .with_no_client_auth();
+            config.alpn_protocols = alpn_protocols;
+            Ok(TlsConnector::from(Arc::new(config)))
+        };
+
+        Ok(Self {
+            http1: connector(Vec::new())?,
+            h2: connector(vec![b"h2".to_vec(), b"http/1.1".to_vec()])?,
+            client_hello,
+        })
+    }
+
+    /// Config with the shared roots, for TLS that does not run over a `Connection`
+    #[cfg(feature = "quic")]
+    pub fn client_config(
+        &self,
+        versions: &[&'static rustls::SupportedProtocolVersion],
+    ) -> Result<rustls::ClientConfig, Error> {
+        Ok(self
+            .client_hello
+            .builder(versions, None)?
+            .with_root_certificates(Arc::clone(&ROOT_CERTS))
+            .with_no_client_auth())
+    }
+}
+
+/// Connectors for the ECH configs of each host and port, `None` when the host publishes none
+static ECH_CONFIGS: Lazy<Mutex<ttl_cache::TtlCache<String, Option<EchConnectors>>>> =
+    Lazy::new(|| Mutex::new(ttl_cache::TtlCache::new(1024)));
+
+/// Built once for each ECH config rather than for each connection
+#[derive(Clone)]
+struct EchConnectors {
+    /// The ECHConfigList they offer
+    list: Vec<u8>,
+    http1: TlsConnector,
+    h2: TlsConnector,
+}
+
+impl EchConnectors {
+    fn new(list: Vec<u8>) -> Result<Self, Error> {
+        let ech = &EchConfig::new(EchConfigListBytes::from(list.clone()), ALL_SUPPORTED_SUITES)?;
+        let client_hello = &PROXY.get().ok_or("")?.tls_connectors.client_hello;
+        let connector = |alpn_protocols| -> Result<TlsConnector, Error> {
+            let mut config = client_hello
+                .builder(rustls::DEFAULT_VERSIONS, Some(ech))?
+                .with_root_certificates(Arc::clone(&ROOT_CERTS))
+                .with_no_client_auth();
+            config.alpn_protocols = alpn_protocols;
+            Ok(TlsConnector::from(Arc::new(config)))
+        };
+
+        Ok(Self {
+            list,
+            http1: connector(Vec::new())?,
+            h2: connector(vec![b"h2".to_vec(), b"http/1.1".to_vec()])?,
+        })
+    }
+}
+
+pub struct TlsClient {
+    ech: Option<EchConnectors>,
+    /// Used instead of the shared connectors when the proxy has "tls" options
+    connector: Option<TlsConnector>,
+    server_name: Option<ServerName<'static>>,
+}
 
 impl TlsClient {
     pub fn new() -> Self {
-        Self()
+        Self {
+            ech: None,
+            connector: None,
+            server_name: None,
+        }
+    }
+
+    /// `connectors` hold the global "client_hello", used when the proxy has none
+    pub fn from_config(
+        config: Option<&TlsClientConfig>,
+        connectors: &TlsConnectors,
+    ) -> Result<Self, Error> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::new()),
+        };
+
+        let mut roots = match config.ca_only {
+            Some(true) => rustls::RootCertStore::empty(),
+            _ => rustls::RootCertStore::clone(&ROOT_CERTS),
+        };
+        if let Some(ca) = &config.ca {
+            for cert in CertificateDer::pem_file_iter(ca)? {
+                roots.add(cert?)?;
+            }
+        }
+
+        let client_hello = match &config.client_hello {
+            Some(client_hello) => ClientHello::new(Some(client_hello))?,
+            None => connectors.client_hello.clone(),
+        };
+        let versions: &[&rustls::SupportedProtocolVersion] = match config.min_version.as_deref() {
+            // GREASE ECH would silently drop TLS 1.2
+            Some("1.2") if client_hello.grease_ech() => return Err("".into()),
+            None | Some("1.2") => rustls::DEFAULT_VERSIONS,
+            Some("1.3") => &[&rustls::version::TLS13],
+            Some(_) => return Err("".into()),
+        };
+        let builder = client_hello.builder(versions, None)?;
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+        let builder = match &config.pin_sha256 {
+            Some(pins) => {
+                let base64 = base64::engine::general_purpose::STANDARD;
+                let pins = pins
+                    .iter()
+                    .map(|pin| base64.decode(pin))
+                    .collect::<Result<_, _>>()?;
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pins }))
+            }
+            None => builder.with_webpki_verifier(verifier),
+        };
+
+        let mut tls = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => {
+                let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+                builder.with_client_auth_cert(certs, PrivateKeyDer::from_pem_file(key)?)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err("".into()),
+        };
+        tls.alpn_protocols = config
+            .alpn
+            .iter()
+            .flatten()
+            .map(|alpn| alpn.as_bytes().to_vec())
+            .collect();
+        tls.enable_sni = config.enable_sni.unwrap_or(true);
+
+        Ok(Self {
+            ech: None,
+            connector: Some(TlsConnector::from(Arc::new(tls))),
+            server_name: config.sni.clone().map(ServerName::try_from).transpose()?,
+        })
+    }
+
+    /// Encrypts the ClientHello with the ECH configs `addr` publishes in its HTTPS record, when "ech" is enabled
+    pub async fn with_ech(addr: &SocketAddr) -> Self {
+        match cached_ech(addr) {
+            Some(ech) => Self { ech, ..Self::new() },
+            None => Self {
+                ech: fetch_ech(addr.clone(), false).await,
+                ..Self::new()
+            },
+        }
+    }
+
+    /// Like `with_ech`, but fetches a missing config in the background for later connections,
+    /// so that the resolver's own connections do not wait on themselves
+    pub fn with_cached_ech(addr: &SocketAddr) -> Self {
+        match cached_ech(addr) {
+            Some(ech) => Self { ech, ..Self::new() },
+            None => {
+                tokio::spawn(fetch_ech(addr.clone(), false));
+                Self::new()
+            }
+        }
+    }
+
+    async fn connect<RW>(
+        &self,
+        alpn_h2: bool,
+        stream: RW,
+        addr: &SocketAddr,
+    ) -> Result<tokio_rustls::client::TlsStream<RW>, Error>
+    where
+        RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let hostname = addr.hostname.to_string();
+        let ech = match &self.ech {
+            Some(ech) => ech,
+            None => {
+                let connectors = &PROXY.get().ok_or("")?.tls_connectors;
+                let connector = match &self.connector {
+                    Some(connector) => connector,
+                    None if alpn_h2 => &connectors.h2,
+                    None => &connectors.http1,
+                };
+                let server_name = match &self.server_name {
+                    Some(server_name) => server_name.clone(),
+                    None => hostname.try_into()?,
+                };
+                return Ok(connector.connect(server_name, stream).await?);
+            }
+        };
+
+        let connector = if alpn_h2 { &ech.h2 } else { &ech.http1 };
+        let result = connector.connect(hostname.try_into()?, stream).await;
+        if let Err(e) = &result {
+            if let Ok(mut configs) = ECH_CONFIGS.lock() {
+                configs.remove(&addr.to_string());
+            }
+            // A rejected config was rotated, so the HTTPS record is looked up again past the DNS cache
+            if is_rejected(e) {
+                // Boxed, as DNS over HTTPS connects through here
+                let fetched = Box::pin(fetch_ech(addr.clone(), true)).await;
+                if let Some(retry) = fetched.filter(|f| f.list != ech.list) {
+                    return Err(Box::new(EchRejected(retry)));
+                }
+            }
+        }
+        Ok(result?)
+    }
+
+    /// Connects with `connect` and performs the handshake, offering ALPN `h2` and `http/1.1` when `alpn_h2`
+    /// and returning whether the server selected `h2`.
+    /// When the server rejects the ECH config, this retries once over a new connection with the configs
+    /// its HTTPS record publishes now, if they changed.
+    pub async fn wrap_connecting<C, F>(
+        mut self,
+        addr: &SocketAddr,
+        alpn_h2: bool,
+        connect: C,
+    ) -> Result<(Connection, bool), Error>
+    where
+        C: Fn() -> F,
+        F: Future<Output = Result<Connection, Error>>,
+    {
+        let stream = match self.connect(alpn_h2, connect().await?, addr).await {
+            Ok(stream) => stream,
+            Err(e) => match e.downcast::<EchRejected>() {
+                Ok(rejected) => {
+                    self.ech = Some(rejected.0);
+                    self.connect(alpn_h2, connect().await?, addr).await?
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let is_h2 = stream.get_ref().1.alpn_protocol() == Some(b"h2");
+
+        Ok((Box::new(stream), is_h2))
+    }
+}
+
+/// Whether `error` is the server's rejection of our ECH config
+fn is_rejected(error: &std::io::Error) -> bool {
+    matches!(
+        error
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<rustls::Error>()),
+        Some(rustls::Error::PeerIncompatible(
+            PeerIncompatible::ServerRejectedEncryptedClientHello(_)
+        ))
+    )
+}
+
+/// The server rejected the ECH config, and these offer the configs published since
+struct EchRejected(EchConnectors);
+
+impl std::fmt::Debug for EchRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "EchRejected")
+    }
+}
+
+impl std::fmt::Display for EchRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server rejected the ECH config")
     }
 }
 
+impl std::error::Error for EchRejected {}
+
+/// `Some(None)` when ECH is disabled or the host publishes no config, and `None` when it is not known yet
+fn cached_ech(addr: &SocketAddr) -> Option<Option<EchConnectors>> {
+    let enabled = PROXY.get().and_then(|p| p.config.ech).unwrap_or(false);
+    if !enabled || addr.hostname.is_ipaddr() {
+        return Some(None);
+    }
+
+    ECH_CONFIGS.lock().ok()?.get(&addr.to_string()).cloned()
+}
+
+/// `fresh` skips the cached HTTPS record
+async fn fetch_ech(addr: SocketAddr, fresh: bool) -> Option<EchConnectors> {
+    let (ech, ttl) = match ech_config_list(&addr.hostname.to_string(), addr.port, fresh).await {
+        Ok(Some((list, ttl))) => (EchConnectors::new(list).ok(), ttl),
+        _ => (None, 300),
+    };
+
+    if let Ok(mut configs) = ECH_CONFIGS.lock() {
+        let ttl = Duration::from_secs(ttl.clamp(60, 3600) as u64);
+        configs.insert(addr.to_string(), ech.clone(), ttl);
+    }
+    ech
+}
+
 #[async_trait]
 impl Layer for TlsClient {
     async fn wrap<RW>(&self, stream: RW, addr: &SocketAddr) -> Result<Connection, Error>
     where
         RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let addr = addr.hostname.to_string();
-        Ok(Box::new(CONNECTOR.connect(addr.try_into()?, stream).await?))
+        Ok(Box::new(self.connect(false, stream, addr).await?))
+    }
+}
+
+/// Also requires the server's public key to match one of the SHA-256 hashes of its SubjectPublicKeyInfo
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let spki = ParsedCertificate::try_from(end_entity)?.subject_public_key_info();
+        let hash = aws_lc_rs::digest::digest(&aws_lc_rs::digest::SHA256, &spki);
+        if !self.pins.iter().any(|pin| pin == hash.as_ref()) {
+            return Err(CertificateError::ApplicationVerificationFailure.into());
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
     }
 }
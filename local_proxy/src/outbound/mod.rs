@@ -11,19 +11,76 @@ pub use socks4::Socks4Proxy;
 pub use socks5::Socks5Proxy;
 
 use crate::{
+    config::FragmentConfig,
     inbound::http::http_proxy::RequestConfig,
-    outbound::layer::Layer,
-    utils::{Body, SocketAddr},
+    outbound::layer::{Fragment, FragmentMode},
+    utils::{Body, HostName, SocketAddr},
     Connection, Error, PROXY,
 };
 
 use async_trait::async_trait;
 use dns_parser::QueryType;
 use dyn_clone::DynClone;
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use hyper::{upgrade::OnUpgrade, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 use tokio::io;
 
+/// Fragment layers parsed from "fragment_options", for requests that turn fragmentation on or
+/// override the configured mode
+pub struct FragmentLayers {
+    configured: Fragment,
+    /// Indexed by `FragmentMode`
+    by_mode: Vec<Fragment>,
+}
+
+impl FragmentLayers {
+    pub fn new(config: Option<&FragmentConfig>) -> Result<Self, Error> {
+        let configured = Fragment::new(config)?;
+        let by_mode = [
+            FragmentMode::Record,
+            FragmentMode::Segment,
+            FragmentMode::Both,
+        ]
+        .into_iter()
+        .map(|m| configured.clone().with_mode(m))
+        .collect();
+
+        Ok(Self {
+            configured,
+            by_mode,
+        })
+    }
+
+    pub fn configured(&self) -> &Fragment {
+        &self.configured
+    }
+}
+
+/// The configured proxies and layers, with the fragment layer `req_conf` asks for outermost
+pub fn proxy_stack(req_conf: &RequestConfig) -> Result<ProxyStack<'static>, Error> {
+    let proxy = PROXY.get().ok_or("")?;
+    let mut proxies: Vec<&dyn ProxyOutBound> = proxy.proxy_stack.iter().map(|p| &**p).collect();
+    let global = matches!(proxy.config.fragment, Some(2..) | None);
+    let fragment = req_conf.fragment.unwrap_or(global);
+    if global && (!fragment || req_conf.fragment_mode.is_some()) {
+        proxies.pop();
+    }
+    if fragment && (!global || req_conf.fragment_mode.is_some()) {
+        let layers = &proxy.fragment_layers;
+        match req_conf.fragment_mode {
+            Some(mode) => proxies.push(&layers.by_mode[mode as usize]),
+            None => proxies.push(&layers.configured),
+        }
+    }
+
+    Ok(Box::new(proxies.into_iter().rev()))
+}
+
 #[async_trait]
 pub trait ProxyOutBound: Send + Sync {
     async fn connect(
@@ -51,41 +108,93 @@ pub trait ProxyOutBoundDefaultMethods: ProxyOutBound {
         addr: &SocketAddr,
     ) -> Result<Connection, Error> {
         let proxy = PROXY.get().ok_or("")?;
-        if proxy.config.doh.is_none() || addr.hostname.is_ipaddr() {
+        if (!proxy.config.has_upstream() && proxy.hosts.is_empty()) || addr.hostname.is_ipaddr() {
             return self.connect(proxies, addr).await;
         }
 
-        let mut doh_failed_v6 = true;
-        let mut doh_failed_v4 = true;
-        let conn;
-        tokio::select! {
-            Ok(conn_) = async {
-                let ip = addr.hostname.dns_resolve(QueryType::AAAA).await?;
-                doh_failed_v6 = false;
-                let addr = SocketAddr::new(ip.ok_or("")?, addr.port);
-                let proxies = dyn_clone::clone_box(&*proxies);
-                let conn = self.connect(proxies, &addr).await?;
-                Ok::<_, Error>(conn)
-            } => conn = conn_,
-            Ok(conn_) = async {
-                let ip = addr.hostname.dns_resolve(QueryType::A).await?;
-                doh_failed_v4 = false;
-                let addr = SocketAddr::new(ip.ok_or("")?, addr.port);
-                let proxies = dyn_clone::clone_box(&*proxies);
-                let conn = self.connect(proxies, &addr).await?;
-                Ok::<_, Error>(conn)
-            } => conn = conn_,
-            else => {
-                if doh_failed_v6 || doh_failed_v4 {
-                    eprintln!("[Warning] DoH failed and fallbacked to DoH disable.");
+        let config = proxy.config.happy_eyeballs.as_ref();
+        let head_start = Duration::from_millis(config.and_then(|c| c.head_start).unwrap_or(250));
+        let attempt_delay =
+            Duration::from_millis(config.and_then(|c| c.attempt_delay).unwrap_or(250));
+
+        let mut resolve_v6 = Box::pin(addr.hostname.dns_resolve(QueryType::AAAA));
+        let mut resolve_v4 = Box::pin(addr.hostname.dns_resolve(QueryType::A));
+        let mut resolved_v6 = false;
+        let mut resolved_v4 = None;
+        let mut doh_failed = false;
+
+        let mut addrs_v6 = VecDeque::new();
+        let mut addrs_v4 = VecDeque::new();
+        let mut prefer_v6 = true;
+        let mut next_attempt = Instant::now();
+        let mut attempts = FuturesUnordered::new();
+        let attempt = |ip: HostName| {
+            let proxies = dyn_clone::clone_box(&*proxies);
+            let addr = SocketAddr::new(ip, addr.port);
+            async move { self.connect(proxies, &addr).await }
+        };
+
+        loop {
+            // IPv6 gets a head start when A records arrive first (RFC 8305 3)
+            let ready =
+                resolved_v6 || resolved_v4.is_some_and(|t: Instant| t.elapsed() >= head_start);
+            if ready && (attempts.is_empty() || Instant::now() >= next_attempt) {
+                // Alternate between families (RFC 8305 4)
+                let ip = if prefer_v6 {
+                    addrs_v6.pop_front().or_else(|| addrs_v4.pop_front())
+                } else {
+                    addrs_v4.pop_front().or_else(|| addrs_v6.pop_front())
+                };
+                if let Some(ip) = ip {
+                    prefer_v6 = !matches!(ip, HostName::V6(_));
+                    attempts.push(attempt(ip));
+                    next_attempt = Instant::now() + attempt_delay;
                 }
-                conn = self
-                    .connect(proxies, addr)
-                    .await?;
+            }
+
+            let resolving = !resolved_v6 || resolved_v4.is_none();
+            if attempts.is_empty() && !resolving {
+                break;
+            }
+            let wake = if !ready {
+                resolved_v4.map(|t| t + head_start)
+            } else if !addrs_v6.is_empty() || !addrs_v4.is_empty() {
+                Some(next_attempt)
+            } else {
+                None
+            };
+
+            tokio::select! {
+                result = &mut resolve_v6, if !resolved_v6 => {
+                    resolved_v6 = true;
+                    match result {
+                        Ok(o) => addrs_v6.extend(o),
+                        Err(_) => doh_failed = true,
+                    }
+                }
+                result = &mut resolve_v4, if resolved_v4.is_none() => {
+                    resolved_v4 = Some(Instant::now());
+                    match result {
+                        Ok(o) => addrs_v4.extend(o),
+                        Err(_) => doh_failed = true,
+                    }
+                }
+                Some(result) = attempts.next(), if !attempts.is_empty() => {
+                    match result {
+                        // Dropping the other attempts cancels them
+                        Ok(conn) => return Ok(conn),
+                        // Start the next attempt right away (RFC 8305 5)
+                        Err(_) => next_attempt = Instant::now(),
+                    }
+                }
+                _ = tokio::time::sleep_until(wake.unwrap_or_else(Instant::now).into()), if wake.is_some() => {}
             }
         }
 
-        Ok(conn)
+        if proxy.config.has_upstream() && doh_failed {
+            eprintln!("[Warning] DoH failed and fallbacked to DoH disable.");
+        }
+        self.connect(proxies, addr).await
     }
 
     async fn http_proxy_(
@@ -106,21 +215,44 @@ pub trait ProxyOutBoundDefaultMethods: ProxyOutBound {
             },
         };
         let addr = SocketAddr::new(hostname, port);
+        let front = match scheme {
+            "https" => PROXY
+                .get()
+                .ok_or("")?
+                .fronting
+                .front(&addr.hostname)
+                .map(|f| SocketAddr::new(f, addr.port)),
+            _ => None,
+        };
         let fake_addr = SocketAddr::new(
-            req_conf.fake_host.clone().unwrap_or(addr.hostname.clone()),
+            req_conf
+                .fake_host
+                .clone()
+                .or_else(|| front.as_ref().map(|f| f.hostname.clone()))
+                .unwrap_or(addr.hostname.clone()),
             addr.port,
         );
 
-        let mut server;
-        if req_conf.doh {
-            server = self.happy_eyeballs(proxies, &fake_addr).await?;
-        } else {
-            server = self.connect(proxies, &fake_addr).await?;
-        }
+        let connect = || async {
+            let proxies = dyn_clone::clone_box(&*proxies);
+            if req_conf.doh {
+                self.happy_eyeballs(proxies, &fake_addr).await
+            } else {
+                self.connect(proxies, &fake_addr).await
+            }
+        };
 
-        if scheme == "https" {
-            server = layer::TlsClient::new().wrap(server, &addr).await?;
-        }
+        let server = if scheme == "https" {
+            // A front is also sent as SNI, while the Host header keeps the real domain
+            let tls_addr = front.as_ref().unwrap_or(&addr);
+            layer::TlsClient::with_ech(tls_addr)
+                .await
+                .wrap_connecting(tls_addr, false, connect)
+                .await?
+                .0
+        } else {
+            connect().await?
+        };
         let server = TokioIo::new(server);
         let (mut sender, conn) = hyper::client::conn::http1::handshake(server).await?;
         tokio::spawn(conn.with_upgrades());
@@ -99,7 +99,7 @@ pub enum HostName {
 }
 
 impl HostName {
-    pub async fn dns_resolve(&self, qtype: QueryType) -> Result<Option<Self>, Error> {
+    pub async fn dns_resolve(&self, qtype: QueryType) -> Result<Vec<Self>, Error> {
         let domain = match self {
             Self::Domain(domain) => domain,
             _ => return Err("".into()),
@@ -112,21 +112,18 @@ impl HostName {
         let result = doh_query(query).await?;
         let response_body = dns_parser::Packet::parse(&result)?;
 
+        let mut addrs = Vec::new();
         for answer in response_body.answers {
             if answer.cls != dns_parser::Class::IN {
                 continue;
             }
             match answer.data {
-                RData::A(addr) => {
-                    return Ok(Some(addr.0.into()));
-                }
-                RData::AAAA(addr) => {
-                    return Ok(Some(addr.0.into()));
-                }
+                RData::A(addr) => addrs.push(addr.0.into()),
+                RData::AAAA(addr) => addrs.push(addr.0.into()),
                 _ => continue,
             }
         }
-        Ok(None)
+        Ok(addrs)
     }
 
     pub fn to_string_url_style(&self) -> String {
@@ -0,0 +1,184 @@
+//! Domain blocklists in hosts format, plain domain lists and AdBlock-style `||domain^` rules.
+
+use super::message::{rcode, rtype, Message, Record};
+use crate::{config::DnsConfig, Error, PROXY};
+
+use std::{collections::HashMap, io::Read};
+
+const TTL: u32 = 60;
+
+pub struct Blocklist {
+    root: Node,
+    nxdomain: bool,
+}
+
+#[derive(Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    block: Option<Scope>,
+    allow: Option<Scope>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Exact,
+    Subdomains,
+}
+
+impl Blocklist {
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self, Error> {
+        let mut blocklist = Self {
+            root: Node::default(),
+            nxdomain: false,
+        };
+        let config = match config {
+            Some(c) => c,
+            None => return Ok(blocklist),
+        };
+
+        blocklist.nxdomain = match config.block_response.as_deref() {
+            None | Some("zero") => false,
+            Some("nxdomain") => true,
+            _ => return Err("".into()),
+        };
+
+        for path in config.blocklists.iter().flatten() {
+            let mut file = String::new();
+            std::fs::File::open(path)?.read_to_string(&mut file)?;
+
+            for line in file.lines() {
+                blocklist.add_rule(line);
+            }
+        }
+
+        Ok(blocklist)
+    }
+
+    fn add_rule(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', '!', '[']) {
+            return;
+        }
+
+        if let Some(rule) = line.strip_prefix("@@||") {
+            if let Some(domain) = rule.strip_suffix('^') {
+                self.root.insert(domain).allow = Some(Scope::Subdomains);
+            }
+        } else if let Some(rule) = line.strip_prefix("||") {
+            if let Some(domain) = rule.strip_suffix('^') {
+                self.root.insert(domain).block = Some(Scope::Subdomains);
+            }
+        } else {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace();
+            let first = match fields.next() {
+                Some(f) => f,
+                None => return,
+            };
+
+            let names: Vec<&str> = if first.parse::<std::net::IpAddr>().is_ok() {
+                fields.collect()
+            } else {
+                vec![first]
+            };
+            for name in names {
+                if matches!(
+                    name,
+                    "localhost"
+                        | "localhost.localdomain"
+                        | "local"
+                        | "broadcasthost"
+                        | "ip6-localhost"
+                        | "ip6-loopback"
+                        | "0.0.0.0"
+                ) {
+                    continue;
+                }
+
+                let node = self.root.insert(name);
+                if node.block.is_none() {
+                    node.block = Some(Scope::Exact);
+                }
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.children.is_empty()
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        let labels: Vec<&str> = name.rsplit('.').collect();
+
+        let mut blocked = false;
+        let mut node = &self.root;
+        for (i, label) in labels.iter().enumerate() {
+            node = match node.children.get(*label) {
+                Some(n) => n,
+                None => break,
+            };
+
+            let is_last = i == labels.len() - 1;
+            let matches = |scope| scope == Scope::Subdomains || is_last;
+            if node.allow.is_some_and(matches) {
+                return false;
+            }
+            if node.block.is_some_and(matches) {
+                blocked = true;
+            }
+        }
+
+        blocked
+    }
+}
+
+impl Node {
+    fn insert(&mut self, domain: &str) -> &mut Self {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            node = node.children.entry(label.to_string()).or_default();
+        }
+        node
+    }
+}
+
+/// Builds a blocking reply, or returns `None` when `query` is not blocked
+pub fn answer(query: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let blocklist = &PROXY.get().ok_or("")?.blocklist;
+    if blocklist.is_empty() {
+        return Ok(None);
+    }
+
+    let query = Message::parse(query)?;
+    let question = match query.questions.first() {
+        Some(q) => q,
+        None => return Ok(None),
+    };
+    if !blocklist.is_blocked(&question.name) {
+        return Ok(None);
+    }
+
+    let mut response = Message::reply(&query);
+    if blocklist.nxdomain {
+        response.set_rcode(rcode::NXDOMAIN);
+    } else {
+        match question.qtype {
+            rtype::A => {
+                response
+                    .answers
+                    .push(Record::new(&question.name, rtype::A, TTL, vec![0; 4]))
+            }
+            rtype::AAAA => {
+                response
+                    .answers
+                    .push(Record::new(&question.name, rtype::AAAA, TTL, vec![0; 16]))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Some(response.to_vec()?))
+}
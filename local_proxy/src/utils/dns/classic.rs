@@ -0,0 +1,170 @@
+//! Classic DNS over UDP and TCP, and DNS over TLS (RFC 7858).
+//!
+//! `udp://` and `tcp://` upstreams are usually intranet resolvers, so they are
+//! reached directly. `tls://` upstreams go through `proxies` like DoH.
+
+use super::{message::read_name, request_config, upstream_addrs};
+use crate::{
+    config::DoHConfig,
+    outbound::{layer::TlsClient, proxy_stack},
+    utils::{HostName, SocketAddr},
+    Error,
+};
+
+use aws_lc_rs::rand::{SecureRandom, SystemRandom};
+use hyper::Uri;
+use std::{str::FromStr, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+
+/// For a whole exchange
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn udp_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let mut result = Err("".into());
+    for addr in lookup(doh_config, endpoint).await? {
+        result = udp_exchange(addr, query).await;
+        if result.is_ok() {
+            break;
+        }
+    }
+    let response = result?;
+
+    // Truncated, so retry over TCP
+    if response[2] & 0x02 != 0 {
+        return tcp_query(query, doh_config, endpoint).await;
+    }
+
+    Ok(response)
+}
+
+/// Sends `query` under a random ID, as the caller's is fixed for caching.
+/// Only a reply with that ID and the same question is taken, and it gets the caller's ID back.
+async fn udp_exchange(addr: std::net::SocketAddr, query: &[u8]) -> Result<Vec<u8>, Error> {
+    let asked = questions(query)?;
+    let mut id = [0; 2];
+    SystemRandom::new().fill(&mut id)?;
+    let mut message = query.to_vec();
+    message.get_mut(0..2).ok_or("")?.copy_from_slice(&id);
+
+    let bind = if addr.is_ipv6() {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+    socket.send(&message).await?;
+
+    let mut response = vec![0; 65535];
+    let len = tokio::time::timeout(TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut response).await?;
+            if len >= 3
+                && response[0..2] == id
+                && questions(&response[..len]).ok().as_ref() == Some(&asked)
+            {
+                return Ok::<_, Error>(len);
+            }
+        }
+    })
+    .await??;
+    response.truncate(len);
+    response[0..2].copy_from_slice(&query[0..2]);
+
+    Ok(response)
+}
+
+/// Lowercased names with the type and class, read without the rest of a possibly truncated message
+fn questions(message: &[u8]) -> Result<Vec<(String, [u8; 4])>, Error> {
+    let count = u16::from_be_bytes(message.get(4..6).ok_or("")?.try_into()?);
+    let mut pos = 12;
+    (0..count)
+        .map(|_| {
+            let (name, end) = read_name(message, pos)?;
+            pos = end + 4;
+            Ok((
+                name.to_ascii_lowercase(),
+                message.get(end..pos).ok_or("")?.try_into()?,
+            ))
+        })
+        .collect()
+}
+
+pub async fn tcp_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let addrs = lookup(doh_config, endpoint).await?;
+    tokio::time::timeout(TIMEOUT, async {
+        let server = TcpStream::connect(&addrs[..]).await?;
+        server.set_nodelay(true)?;
+
+        exchange(server, query).await
+    })
+    .await?
+}
+
+pub async fn tls_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    tokio::time::timeout(TIMEOUT, tls_exchange(query, doh_config, endpoint)).await?
+}
+
+async fn tls_exchange(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let req_conf = request_config(doh_config);
+
+    let hostname = HostName::from_str(endpoint.host().ok_or("")?)?;
+    let addr = SocketAddr::new(hostname, endpoint.port_u16().unwrap_or(853));
+    let fake_addr = SocketAddr::new(
+        req_conf.fake_host.clone().unwrap_or(addr.hostname.clone()),
+        addr.port,
+    );
+
+    let (server, _) = TlsClient::with_cached_ech(&addr)
+        .wrap_connecting(&addr, false, || async {
+            let mut proxies = proxy_stack(&req_conf)?;
+            proxies.next().ok_or("")?.connect(proxies, &fake_addr).await
+        })
+        .await?;
+
+    exchange(server, query).await
+}
+
+async fn exchange<RW>(mut server: RW, query: &[u8]) -> Result<Vec<u8>, Error>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&u16::try_from(query.len())?.to_be_bytes());
+    message.extend_from_slice(query);
+    server.write_all(&message).await?;
+    server.flush().await?;
+
+    let len = server.read_u16().await?;
+    let mut response = vec![0; len.into()];
+    server.read_exact(&mut response).await?;
+
+    Ok(response)
+}
+
+async fn lookup(
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<std::net::SocketAddr>, Error> {
+    let host = endpoint.host().ok_or("")?;
+    upstream_addrs(doh_config, host, endpoint.port_u16().unwrap_or(53)).await
+}
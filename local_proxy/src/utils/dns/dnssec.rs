@@ -0,0 +1,1332 @@
+//! DNSSEC validation (RFC 4033, RFC 4034, RFC 4035) of answers from the default upstream.
+//!
+//! Chains of trust are built down from the root trust anchors with DS and DNSKEY queries
+//! sent through the same upstream. Negative answers and wildcard expansions from secure zones
+//! must come with NSEC (RFC 4035 5.4) or NSEC3 (RFC 5155 8) records proving them, and are
+//! bogus otherwise.
+
+use super::{
+    edns::{self, AUTHENTIC_DATA},
+    forward,
+    message::{rcode, read_name, rtype, write_name, Message, Record},
+};
+use crate::Error;
+
+use aws_lc_rs::{digest, signature};
+use once_cell::sync::Lazy;
+use std::{
+    cmp::Ordering,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::RwLock;
+use ttl_cache::TtlCache;
+
+/// KSK-2017 and KSK-2024 (https://data.iana.org/root-anchors/root-anchors.xml)
+const ROOT_ANCHORS: [(u16, u8, u8, &str); 2] = [
+    (
+        20326,
+        8,
+        2,
+        "e06d44b80b8f1d39a95c0b0d7c65d08458e880409bbc683457104237c7f8ec8d",
+    ),
+    (
+        38696,
+        8,
+        2,
+        "683d2d0acb8c9b712a1948b27f741219298d0a450d612c483af444a4c0fb2b16",
+    ),
+];
+
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// Proofs with NSEC3 records above this are treated as insecure rather than hashed (RFC 9276 3.2)
+const MAX_NSEC3_ITERATIONS: u16 = 50;
+
+static DELEGATIONS: Lazy<RwLock<TtlCache<String, Delegation>>> =
+    Lazy::new(|| RwLock::new(TtlCache::new(4096)));
+
+#[derive(Clone)]
+enum Delegation {
+    Secure(Arc<Vec<Key>>),
+    NotCut,
+    Insecure,
+}
+
+struct Key {
+    tag: u16,
+    algorithm: u8,
+    public_key: Vec<u8>,
+}
+
+struct Ds {
+    tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    digest: Vec<u8>,
+}
+
+/// Validates `response`, setting AD when every RRset is secure.
+/// Returns an error for bogus responses, which should become SERVFAIL.
+pub async fn validate(query: &Message, response: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut message = Message::parse(response)?;
+    let nxdomain = match message.rcode() {
+        rcode::NOERROR => false,
+        rcode::NXDOMAIN => true,
+        _ => return Ok(response.to_vec()),
+    };
+    let question = query.questions.first().ok_or("")?;
+
+    let mut secure = check_section(&message.answers).await?;
+
+    let answered = message
+        .answers
+        .iter()
+        .any(|r| r.rtype == question.qtype || question.qtype == 255);
+    let expanded = expansions(&message.answers);
+    if !answered || !expanded.is_empty() {
+        let target = cname_target(&message.answers, &question.name)?;
+        let proof = &message.authorities;
+        if proof
+            .iter()
+            .any(|r| matches!(r.rtype, rtype::NSEC | rtype::NSEC3))
+        {
+            secure &= check_section(proof).await?;
+        } else if !expanded.is_empty() || zone_keys(&target).await?.is_some() {
+            // A secure zone must prove the denial, and that the wildcard did not hide a name
+            return Err("".into());
+        } else {
+            secure = false;
+        }
+
+        // Unsigned proofs only come from insecure zones
+        if secure {
+            for (name, labels) in &expanded {
+                secure &= proves_expansion(proof, name, *labels)?;
+            }
+            if !answered {
+                secure &= denies(proof, &target, question.qtype, nxdomain)?;
+            }
+        }
+    }
+
+    if secure {
+        message.flags |= AUTHENTIC_DATA;
+    } else {
+        message.flags &= !AUTHENTIC_DATA;
+    }
+    message.to_vec()
+}
+
+/// Checks every RRset in `records`, returning whether all of them are secure
+async fn check_section(records: &[Record]) -> Result<bool, Error> {
+    let has_dname = records.iter().any(|r| r.rtype == rtype::DNAME);
+
+    let mut secure = true;
+    let mut checked: Vec<(String, u16)> = Vec::new();
+    for record in records {
+        let name = record.name.to_ascii_lowercase();
+        if matches!(record.rtype, rtype::RRSIG | rtype::OPT | rtype::NS)
+            || checked.contains(&(name.clone(), record.rtype))
+        {
+            continue;
+        }
+        checked.push((name.clone(), record.rtype));
+
+        let signed = signatures(records, &name, record.rtype).next().is_some();
+        if !signed && record.rtype == rtype::CNAME && has_dname {
+            // Synthesized from a DNAME (RFC 6672 5.3.1)
+            continue;
+        }
+        secure &= check_rrset(records, &name, record.rtype).await?;
+    }
+
+    Ok(secure)
+}
+
+/// Returns whether the RRset is secure, or an error when it is bogus
+async fn check_rrset(records: &[Record], name: &str, rrtype: u16) -> Result<bool, Error> {
+    let signer = match signatures(records, name, rrtype).next() {
+        Some(s) => read_name(&s.data, 18)?.0.to_ascii_lowercase(),
+        None => {
+            return match zone_keys(name).await? {
+                Some(_) => Err("".into()),
+                None => Ok(false),
+            }
+        }
+    };
+    if !is_subdomain(name, &signer) {
+        return Err("".into());
+    }
+
+    match zone_keys(&signer).await? {
+        Some((zone, keys)) if zone == signer => {
+            verify_rrset(records, name, rrtype, &zone, &keys)?;
+            Ok(true)
+        }
+        Some(_) => Err("".into()),
+        None => Ok(false),
+    }
+}
+
+/// Follows the chain of trust from the root, returning the deepest secure zone enclosing `name`
+/// with its keys, or `None` when `name` is under an insecure delegation
+async fn zone_keys(name: &str) -> Result<Option<(String, Arc<Vec<Key>>)>, Error> {
+    let mut zone = String::new();
+    let mut keys = match root_keys().await? {
+        Delegation::Secure(k) => k,
+        _ => return Ok(None),
+    };
+
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+    for i in (0..labels.len()).rev() {
+        let child = labels[i..].join(".");
+        match delegation(&child, &zone, &keys).await? {
+            Delegation::Secure(k) => {
+                zone = child;
+                keys = k;
+            }
+            Delegation::NotCut => {}
+            Delegation::Insecure => return Ok(None),
+        }
+    }
+
+    Ok(Some((zone, keys)))
+}
+
+async fn root_keys() -> Result<Delegation, Error> {
+    if let Some(d) = DELEGATIONS.read().await.get(".") {
+        return Ok(d.clone());
+    }
+
+    let anchors = ROOT_ANCHORS
+        .iter()
+        .map(|(tag, algorithm, digest_type, digest)| {
+            Ok(Ds {
+                tag: *tag,
+                algorithm: *algorithm,
+                digest_type: *digest_type,
+                digest: from_hex(digest)?,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    let delegation = dnskeys("", &anchors).await?;
+
+    DELEGATIONS
+        .write()
+        .await
+        .insert(".".to_string(), delegation.clone(), CACHE_TTL);
+    Ok(delegation)
+}
+
+/// Finds out whether `child` is a zone cut below the secure `parent` zone
+async fn delegation(child: &str, parent: &str, parent_keys: &[Key]) -> Result<Delegation, Error> {
+    if let Some(d) = DELEGATIONS.read().await.get(child) {
+        return Ok(d.clone());
+    }
+
+    let response = lookup(child, rtype::DS).await?;
+    let ds = response
+        .answers
+        .iter()
+        .filter(|r| r.rtype == rtype::DS && r.name.eq_ignore_ascii_case(child))
+        .map(|r| {
+            Ok(Ds {
+                tag: u16::from_be_bytes(r.data.get(0..2).ok_or("")?.try_into()?),
+                algorithm: *r.data.get(2).ok_or("")?,
+                digest_type: *r.data.get(3).ok_or("")?,
+                digest: r.data.get(4..).ok_or("")?.to_vec(),
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let delegation = if !ds.is_empty() {
+        verify_rrset(&response.answers, child, rtype::DS, parent, parent_keys)?;
+        dnskeys(child, &ds).await?
+    } else if response
+        .answers
+        .iter()
+        .any(|r| r.rtype == rtype::CNAME && r.name.eq_ignore_ascii_case(child))
+    {
+        // An alias cannot be a delegation point
+        verify_rrset(&response.answers, child, rtype::CNAME, parent, parent_keys)?;
+        Delegation::NotCut
+    } else {
+        denial(&response, child, parent, parent_keys)?
+    };
+
+    DELEGATIONS
+        .write()
+        .await
+        .insert(child.to_string(), delegation.clone(), CACHE_TTL);
+    Ok(delegation)
+}
+
+/// Reads the parent's signed proof that `child` has no DS
+fn denial(
+    response: &Message,
+    child: &str,
+    parent: &str,
+    parent_keys: &[Key],
+) -> Result<Delegation, Error> {
+    let proof = &response.authorities;
+    for record in proof {
+        if matches!(record.rtype, rtype::NSEC | rtype::NSEC3 | rtype::SOA) {
+            verify_rrset(proof, &record.name, record.rtype, parent, parent_keys)?;
+        }
+    }
+
+    if let Some(bitmap) = nsec_matching(proof, child)? {
+        return Ok(delegation_from_bitmap(bitmap));
+    }
+    if nsec_covering(proof, child)?.is_some() {
+        return Ok(Delegation::NotCut);
+    }
+
+    let nsec3s = match nsec3s(proof)? {
+        Some(nsec3s) => nsec3s,
+        None => return Ok(Delegation::Insecure),
+    };
+    if let Some(nsec3) = nsec3_matching(&nsec3s, child)? {
+        return Ok(delegation_from_bitmap(&nsec3.bitmap));
+    }
+    match closest_encloser(&nsec3s, child)? {
+        // Opt-out spans only cover unsigned delegations (RFC 5155 6, 8.6)
+        Some((_, cover)) if cover.opt_out => Ok(Delegation::Insecure),
+        Some(_) if response.rcode() == rcode::NXDOMAIN => Ok(Delegation::NotCut),
+        _ => Err("".into()),
+    }
+}
+
+fn delegation_from_bitmap(bitmap: &[u8]) -> Delegation {
+    if has_type(bitmap, rtype::NS) && !has_type(bitmap, rtype::SOA) {
+        Delegation::Insecure
+    } else {
+        Delegation::NotCut
+    }
+}
+
+/// Checks the proof that `name` has no `qtype` RRset, or does not exist at all when `nxdomain`.
+/// Returns whether it is secure, which it is not when it relies on an NSEC3 opt-out span.
+fn denies(proof: &[Record], name: &str, qtype: u16, nxdomain: bool) -> Result<bool, Error> {
+    let lacks_type = |bitmap: &[u8]| !has_type(bitmap, qtype) && !has_type(bitmap, rtype::CNAME);
+
+    if !nxdomain {
+        if let Some(bitmap) = nsec_matching(proof, name)? {
+            return if lacks_type(bitmap) {
+                Ok(true)
+            } else {
+                Err("".into())
+            };
+        }
+    }
+    if let Some((owner, next)) = nsec_covering(proof, name)? {
+        if !nxdomain && is_subdomain(&next, name) {
+            // Empty non-terminal
+            return Ok(true);
+        }
+
+        let encloser = [common_ancestor(name, &owner), common_ancestor(name, &next)]
+            .into_iter()
+            .max_by_key(|a| a.len())
+            .unwrap_or_default();
+        let wildcard = wildcard_of(&encloser);
+        let proven = if nxdomain {
+            nsec_covering(proof, &wildcard)?.is_some()
+        } else {
+            nsec_matching(proof, &wildcard)?.is_some_and(lacks_type)
+        };
+        return if proven { Ok(true) } else { Err("".into()) };
+    }
+
+    let nsec3s = match nsec3s(proof)? {
+        Some(nsec3s) => nsec3s,
+        None => return Ok(false),
+    };
+    if !nxdomain {
+        if let Some(nsec3) = nsec3_matching(&nsec3s, name)? {
+            return if lacks_type(&nsec3.bitmap) {
+                Ok(true)
+            } else {
+                Err("".into())
+            };
+        }
+    }
+    let (encloser, cover) = closest_encloser(&nsec3s, name)?.ok_or("")?;
+    let wildcard = wildcard_of(&encloser);
+    if nxdomain {
+        nsec3_covering(&nsec3s, &wildcard)?.ok_or("")?;
+        return Ok(!cover.opt_out);
+    }
+    match nsec3_matching(&nsec3s, &wildcard)? {
+        Some(nsec3) if lacks_type(&nsec3.bitmap) => Ok(!cover.opt_out),
+        // An unsigned delegation in an opt-out span (RFC 5155 8.6)
+        None if qtype == rtype::DS && cover.opt_out => Ok(false),
+        _ => Err("".into()),
+    }
+}
+
+/// Checks the proof that `name`, expanded from a wildcard under its last `labels` labels, does not exist
+fn proves_expansion(proof: &[Record], name: &str, labels: usize) -> Result<bool, Error> {
+    if nsec_covering(proof, name)?.is_some() {
+        return Ok(true);
+    }
+
+    let name_labels = split_labels(name);
+    let next_closer = name_labels[(name_labels.len() - labels - 1)..].join(".");
+    let nsec3s = match nsec3s(proof)? {
+        Some(nsec3s) => nsec3s,
+        None => return Ok(false),
+    };
+    match nsec3_covering(&nsec3s, &next_closer)? {
+        Some(cover) => Ok(!cover.opt_out),
+        None => Err("".into()),
+    }
+}
+
+/// Signed names in `records` that were expanded from a wildcard, with the labels count of their RRSIG
+fn expansions(records: &[Record]) -> Vec<(String, usize)> {
+    let mut expanded = Vec::new();
+    for sig in records.iter().filter(|r| r.rtype == rtype::RRSIG) {
+        let labels = match sig.data.get(3) {
+            Some(l) => *l as usize,
+            None => continue,
+        };
+        let name = sig.name.trim_end_matches('.').to_ascii_lowercase();
+        let name_labels = split_labels(&name);
+        if labels < name_labels.len()
+            && name_labels.first() != Some(&"*")
+            && !expanded.contains(&(name.clone(), labels))
+        {
+            expanded.push((name, labels));
+        }
+    }
+
+    expanded
+}
+
+/// Follows the CNAME chain in `records` from `name`
+fn cname_target(records: &[Record], name: &str) -> Result<String, Error> {
+    let mut target = name.to_ascii_lowercase();
+    for _ in 0..records.len() {
+        match records
+            .iter()
+            .find(|r| r.rtype == rtype::CNAME && r.name.eq_ignore_ascii_case(&target))
+        {
+            Some(r) => target = read_name(&r.data, 0)?.0.to_ascii_lowercase(),
+            None => break,
+        }
+    }
+
+    Ok(target)
+}
+
+/// The type bitmap of the NSEC owned by `name`
+fn nsec_matching<'a>(proof: &'a [Record], name: &str) -> Result<Option<&'a [u8]>, Error> {
+    for record in proof.iter().filter(|r| r.rtype == rtype::NSEC) {
+        if record.name.eq_ignore_ascii_case(name) {
+            let end = read_name(&record.data, 0)?.1;
+            return Ok(Some(record.data.get(end..).ok_or("")?));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The owner and next name of the NSEC whose span holds `name`
+fn nsec_covering(proof: &[Record], name: &str) -> Result<Option<(String, String)>, Error> {
+    for record in proof.iter().filter(|r| r.rtype == rtype::NSEC) {
+        let (next, end) = read_name(&record.data, 0)?;
+        let bitmap = record.data.get(end..).ok_or("")?;
+        let owner = &record.name;
+
+        // The parent's NSEC at a delegation says nothing about names below it
+        let delegation = has_type(bitmap, rtype::NS) && !has_type(bitmap, rtype::SOA);
+        if delegation && is_subdomain(name, owner) {
+            continue;
+        }
+
+        let after_owner = canonical_cmp(owner, name) == Ordering::Less;
+        let before_next = canonical_cmp(name, &next) == Ordering::Less;
+        let covers = if canonical_cmp(owner, &next) == Ordering::Less {
+            after_owner && before_next
+        } else {
+            // The last NSEC of the zone points back to the apex
+            after_owner
+        };
+        if covers {
+            return Ok(Some((
+                owner.to_ascii_lowercase(),
+                next.to_ascii_lowercase(),
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+struct Nsec3 {
+    zone: String,
+    hash: String,
+    next: String,
+    iterations: u16,
+    salt: Vec<u8>,
+    opt_out: bool,
+    bitmap: Vec<u8>,
+}
+
+impl Nsec3 {
+    fn hash_of(&self, name: &str) -> Result<Option<String>, Error> {
+        if !is_subdomain(name, &self.zone) {
+            return Ok(None);
+        }
+        Ok(Some(nsec3_hash(name, &self.salt, self.iterations)?))
+    }
+}
+
+/// Parses the NSEC3 records using SHA-1, the only hash algorithm defined.
+/// `None` when one of them has more than `MAX_NSEC3_ITERATIONS`.
+fn nsec3s(proof: &[Record]) -> Result<Option<Vec<Nsec3>>, Error> {
+    let mut nsec3s = Vec::new();
+    for record in proof.iter().filter(|r| r.rtype == rtype::NSEC3) {
+        let data = &record.data;
+        if *data.first().ok_or("")? != 1 {
+            continue;
+        }
+        let iterations = u16::from_be_bytes(data.get(2..4).ok_or("")?.try_into()?);
+        if iterations > MAX_NSEC3_ITERATIONS {
+            return Ok(None);
+        }
+        let salt_len = *data.get(4).ok_or("")? as usize;
+        let hash_len = *data.get(5 + salt_len).ok_or("")? as usize;
+        let name = record.name.trim_end_matches('.').to_ascii_lowercase();
+        let (hash, zone) = name.split_once('.').unwrap_or((&name, ""));
+
+        nsec3s.push(Nsec3 {
+            zone: zone.to_string(),
+            hash: hash.to_string(),
+            next: base32hex(
+                data.get((6 + salt_len)..(6 + salt_len + hash_len))
+                    .ok_or("")?,
+            ),
+            iterations,
+            salt: data.get(5..(5 + salt_len)).ok_or("")?.to_vec(),
+            opt_out: data.get(1).ok_or("")? & 0x01 != 0,
+            bitmap: data.get((6 + salt_len + hash_len)..).ok_or("")?.to_vec(),
+        });
+    }
+
+    Ok(Some(nsec3s))
+}
+
+fn nsec3_matching<'a>(nsec3s: &'a [Nsec3], name: &str) -> Result<Option<&'a Nsec3>, Error> {
+    for nsec3 in nsec3s {
+        if nsec3.hash_of(name)?.as_deref() == Some(&nsec3.hash) {
+            return Ok(Some(nsec3));
+        }
+    }
+
+    Ok(None)
+}
+
+fn nsec3_covering<'a>(nsec3s: &'a [Nsec3], name: &str) -> Result<Option<&'a Nsec3>, Error> {
+    for nsec3 in nsec3s {
+        let hash = match nsec3.hash_of(name)? {
+            Some(h) => h,
+            None => continue,
+        };
+        // base32hex keeps the order of the hashes
+        let covers = if nsec3.hash < nsec3.next {
+            nsec3.hash < hash && hash < nsec3.next
+        } else {
+            nsec3.hash < hash || hash < nsec3.next
+        };
+        if covers {
+            return Ok(Some(nsec3));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Closest encloser proof (RFC 5155 8.3): the closest existing ancestor of `name`,
+/// with the NSEC3 covering the next closer name
+fn closest_encloser<'a>(
+    nsec3s: &'a [Nsec3],
+    name: &str,
+) -> Result<Option<(String, &'a Nsec3)>, Error> {
+    let labels = split_labels(name);
+    for i in 1..=labels.len() {
+        let encloser = labels[i..].join(".");
+        let matching = match nsec3_matching(nsec3s, &encloser)? {
+            Some(m) => m,
+            None => continue,
+        };
+        if has_type(&matching.bitmap, rtype::NS) && !has_type(&matching.bitmap, rtype::SOA) {
+            // Names below a delegation are not in this zone
+            return Ok(None);
+        }
+
+        let next_closer = labels[(i - 1)..].join(".");
+        return Ok(nsec3_covering(nsec3s, &next_closer)?.map(|cover| (encloser, cover)));
+    }
+
+    Ok(None)
+}
+
+/// Fetches the DNSKEY RRset of `zone` and authenticates it with `ds`
+async fn dnskeys(zone: &str, ds: &[Ds]) -> Result<Delegation, Error> {
+    let supported: Vec<&Ds> = ds
+        .iter()
+        .filter(|d| is_supported(d.algorithm) && digest_algorithm(d.digest_type).is_some())
+        .collect();
+    if supported.is_empty() {
+        // RFC 4035 5.2: treated as unsigned
+        return Ok(Delegation::Insecure);
+    }
+
+    let response = lookup(zone, rtype::DNSKEY).await?;
+    let mut keys = Vec::new();
+    let mut trusted = Vec::new();
+    for record in &response.answers {
+        if record.rtype != rtype::DNSKEY || !record.name.eq_ignore_ascii_case(zone) {
+            continue;
+        }
+        let flags = u16::from_be_bytes(record.data.get(0..2).ok_or("")?.try_into()?);
+        if flags & 0x0100 == 0 {
+            continue;
+        }
+
+        let key = Key {
+            tag: key_tag(&record.data),
+            algorithm: *record.data.get(3).ok_or("")?,
+            public_key: record.data.get(4..).ok_or("")?.to_vec(),
+        };
+        let matches_ds = supported.iter().any(|d| {
+            d.tag == key.tag
+                && d.algorithm == key.algorithm
+                && ds_digest(zone, &record.data, d.digest_type).as_deref() == Some(&d.digest[..])
+        });
+        if matches_ds {
+            trusted.push(Key {
+                tag: key.tag,
+                algorithm: key.algorithm,
+                public_key: key.public_key.clone(),
+            });
+        }
+        keys.push(key);
+    }
+
+    verify_rrset(&response.answers, zone, rtype::DNSKEY, zone, &trusted)?;
+    Ok(Delegation::Secure(Arc::new(keys)))
+}
+
+async fn lookup(name: &str, qtype: u16) -> Result<Message, Error> {
+    let (query, _) = edns::normalize(&Message::query(0xabcd, name, qtype).to_vec()?)?;
+    let response = Message::parse(&forward(&query).await?)?;
+    if !matches!(response.rcode(), rcode::NOERROR | rcode::NXDOMAIN) {
+        return Err("".into());
+    }
+
+    Ok(response)
+}
+
+fn signatures<'a>(
+    records: &'a [Record],
+    name: &'a str,
+    rrtype: u16,
+) -> impl Iterator<Item = &'a Record> {
+    records.iter().filter(move |r| {
+        r.rtype == rtype::RRSIG
+            && r.name.eq_ignore_ascii_case(name)
+            && r.data.get(0..2) == Some(&rrtype.to_be_bytes()[..])
+    })
+}
+
+/// Succeeds when an RRSIG made by `zone` with one of `keys` verifies the RRset
+fn verify_rrset(
+    records: &[Record],
+    name: &str,
+    rrtype: u16,
+    zone: &str,
+    keys: &[Key],
+) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as u32;
+
+    for sig in signatures(records, name, rrtype) {
+        let data = &sig.data;
+        let algorithm = *data.get(2).ok_or("")?;
+        let labels = *data.get(3).ok_or("")? as usize;
+        let original_ttl = data.get(4..8).ok_or("")?;
+        let expiration = u32::from_be_bytes(data.get(8..12).ok_or("")?.try_into()?);
+        let inception = u32::from_be_bytes(data.get(12..16).ok_or("")?.try_into()?);
+        let tag = u16::from_be_bytes(data.get(16..18).ok_or("")?.try_into()?);
+        let (signer, end) = read_name(data, 18)?;
+
+        // Serial number arithmetic (RFC 4034 3.1.5)
+        let in_period =
+            (now.wrapping_sub(inception) as i32) >= 0 && (expiration.wrapping_sub(now) as i32) >= 0;
+        if !signer.eq_ignore_ascii_case(zone) || !in_period {
+            continue;
+        }
+
+        let signed = signed_data(records, name, rrtype, labels, original_ttl, data)?;
+        let signature = &data[end..];
+        let verified = keys
+            .iter()
+            .filter(|k| k.tag == tag && k.algorithm == algorithm)
+            .any(|k| verify(k, &signed, signature));
+        if verified {
+            return Ok(());
+        }
+    }
+
+    Err("".into())
+}
+
+/// Builds the data covered by an RRSIG (RFC 4034 3.1.8.1, 6.2, 6.3)
+fn signed_data(
+    records: &[Record],
+    name: &str,
+    rrtype: u16,
+    labels: usize,
+    original_ttl: &[u8],
+    rrsig: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut signed = rrsig[..18].to_vec();
+    write_name(&mut signed, &read_name(rrsig, 18)?.0.to_ascii_lowercase())?;
+
+    // Wildcard expansions are signed as the wildcard owner (RFC 4035 5.3.2)
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    let name_labels: Vec<&str> = name.split('.').filter(|l| !l.is_empty()).collect();
+    let owner = if labels < name_labels.len() {
+        format!(
+            "*.{}",
+            name_labels[(name_labels.len() - labels)..].join(".")
+        )
+    } else {
+        name.clone()
+    };
+    let mut owner_wire = Vec::new();
+    write_name(&mut owner_wire, &owner)?;
+
+    let mut rdatas = records
+        .iter()
+        .filter(|r| r.rtype == rrtype && r.name.eq_ignore_ascii_case(&name))
+        .map(|r| canonical_rdata(r.rtype, &r.data))
+        .collect::<Result<Vec<_>, Error>>()?;
+    rdatas.sort();
+    rdatas.dedup();
+
+    for rdata in rdatas {
+        signed.extend_from_slice(&owner_wire);
+        signed.extend_from_slice(&rrtype.to_be_bytes());
+        signed.extend_from_slice(&super::message::CLASS_IN.to_be_bytes());
+        signed.extend_from_slice(original_ttl);
+        signed.extend_from_slice(&u16::try_from(rdata.len())?.to_be_bytes());
+        signed.extend_from_slice(&rdata);
+    }
+
+    Ok(signed)
+}
+
+/// Lowercases the names embedded in RDATA (RFC 4034 6.2, RFC 6840 5.1)
+fn canonical_rdata(rrtype: u16, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut canonical = Vec::with_capacity(data.len());
+    match rrtype {
+        rtype::NS | rtype::CNAME | rtype::PTR | rtype::DNAME => {
+            write_name(&mut canonical, &read_name(data, 0)?.0.to_ascii_lowercase())?;
+        }
+        rtype::MX | rtype::SRV => {
+            let skip = if rrtype == rtype::MX { 2 } else { 6 };
+            canonical.extend_from_slice(data.get(..skip).ok_or("")?);
+            write_name(
+                &mut canonical,
+                &read_name(data, skip)?.0.to_ascii_lowercase(),
+            )?;
+        }
+        rtype::SOA => {
+            let (mname, next) = read_name(data, 0)?;
+            let (rname, next) = read_name(data, next)?;
+            write_name(&mut canonical, &mname.to_ascii_lowercase())?;
+            write_name(&mut canonical, &rname.to_ascii_lowercase())?;
+            canonical.extend_from_slice(data.get(next..).ok_or("")?);
+        }
+        _ => canonical.extend_from_slice(data),
+    }
+
+    Ok(canonical)
+}
+
+fn is_supported(algorithm: u8) -> bool {
+    matches!(algorithm, 5 | 7 | 8 | 10 | 13 | 14 | 15)
+}
+
+fn verify(key: &Key, message: &[u8], sig: &[u8]) -> bool {
+    let public_key = &key.public_key;
+    match key.algorithm {
+        5 | 7 | 8 | 10 => {
+            // RFC 3110 2: exponent length, exponent, modulus
+            let (exponent_len, start) = match public_key.first() {
+                Some(0) => match public_key.get(1..3) {
+                    Some(l) => (u16::from_be_bytes([l[0], l[1]]) as usize, 3),
+                    None => return false,
+                },
+                Some(l) => (*l as usize, 1),
+                None => return false,
+            };
+            let (e, n) = match public_key.get(start..(start + exponent_len)) {
+                Some(e) => (e, &public_key[(start + exponent_len)..]),
+                None => return false,
+            };
+            let params = match key.algorithm {
+                8 => &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY,
+                10 => &signature::RSA_PKCS1_1024_8192_SHA512_FOR_LEGACY_USE_ONLY,
+                _ => &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY,
+            };
+            signature::RsaPublicKeyComponents { n, e }
+                .verify(params, message, sig)
+                .is_ok()
+        }
+        13 | 14 => {
+            let algorithm = if key.algorithm == 13 {
+                &signature::ECDSA_P256_SHA256_FIXED
+            } else {
+                &signature::ECDSA_P384_SHA384_FIXED
+            };
+            let mut point = vec![0x04];
+            point.extend_from_slice(public_key);
+            signature::UnparsedPublicKey::new(algorithm, point)
+                .verify(message, sig)
+                .is_ok()
+        }
+        15 => signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+            .verify(message, sig)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+fn digest_algorithm(digest_type: u8) -> Option<&'static digest::Algorithm> {
+    match digest_type {
+        1 => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+        2 => Some(&digest::SHA256),
+        4 => Some(&digest::SHA384),
+        _ => None,
+    }
+}
+
+/// RFC 4034 5.1.4
+fn ds_digest(zone: &str, dnskey: &[u8], digest_type: u8) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    write_name(&mut data, &zone.to_ascii_lowercase()).ok()?;
+    data.extend_from_slice(dnskey);
+
+    Some(
+        digest::digest(digest_algorithm(digest_type)?, &data)
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// RFC 4034 Appendix B
+fn key_tag(dnskey: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, b) in dnskey.iter().enumerate() {
+        ac += if i & 1 == 0 {
+            (*b as u32) << 8
+        } else {
+            *b as u32
+        };
+    }
+    ac += (ac >> 16) & 0xffff;
+    (ac & 0xffff) as u16
+}
+
+/// Hashed owner name in base32hex (RFC 5155 5)
+fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Result<String, Error> {
+    Ok(base32hex(&nsec3_digest(name, salt, iterations)?))
+}
+
+fn nsec3_digest(name: &str, salt: &[u8], iterations: u16) -> Result<Vec<u8>, Error> {
+    let mut hash = Vec::new();
+    write_name(&mut hash, &name.to_ascii_lowercase())?;
+
+    for _ in 0..=iterations {
+        hash.extend_from_slice(salt);
+        hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, &hash)
+            .as_ref()
+            .to_vec();
+    }
+
+    Ok(hash)
+}
+
+/// RFC 4648 7, lowercased and without padding
+fn base32hex(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuv";
+    let mut encoded = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for b in data {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Looks up `rrtype` in an NSEC or NSEC3 type bitmap (RFC 4034 4.1.2)
+fn has_type(mut bitmap: &[u8], rrtype: u16) -> bool {
+    let window = (rrtype >> 8) as u8;
+    let bit = (rrtype & 0xff) as usize;
+    while bitmap.len() >= 2 {
+        let len = bitmap[1] as usize;
+        let block = match bitmap.get(2..(2 + len)) {
+            Some(b) => b,
+            None => return false,
+        };
+        if bitmap[0] == window {
+            return block
+                .get(bit / 8)
+                .is_some_and(|b| b & (0x80 >> (bit % 8)) != 0);
+        }
+        bitmap = &bitmap[(2 + len)..];
+    }
+
+    false
+}
+
+fn is_subdomain(name: &str, zone: &str) -> bool {
+    let name = name.trim_end_matches('.');
+    let zone = zone.trim_end_matches('.');
+    zone.is_empty()
+        || name.eq_ignore_ascii_case(zone)
+        || (name.len() > zone.len()
+            && name[(name.len() - zone.len())..].eq_ignore_ascii_case(zone)
+            && name[..(name.len() - zone.len())].ends_with('.'))
+}
+
+fn split_labels(name: &str) -> Vec<&str> {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+fn wildcard_of(encloser: &str) -> String {
+    if encloser.is_empty() {
+        "*".to_string()
+    } else {
+        format!("*.{}", encloser)
+    }
+}
+
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a = a.to_ascii_lowercase();
+    let b = b.to_ascii_lowercase();
+    let mut common: Vec<&str> = split_labels(&a)
+        .into_iter()
+        .rev()
+        .zip(split_labels(&b).into_iter().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect();
+    common.reverse();
+    common.join(".")
+}
+
+/// Canonical DNS name order (RFC 4034 6.1)
+fn canonical_cmp(a: &str, b: &str) -> Ordering {
+    let a = a.to_ascii_lowercase();
+    let b = b.to_ascii_lowercase();
+    let a_labels = split_labels(&a).into_iter().rev().map(str::as_bytes);
+    let b_labels = split_labels(&b).into_iter().rev().map(str::as_bytes);
+    a_labels.cmp(b_labels)
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, Error> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(s.get(i..(i + 2)).ok_or("")?, 16)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lc_rs::{
+        rand::SystemRandom,
+        rsa::{KeySize, PublicKeyComponents},
+        signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, RsaKeyPair},
+    };
+    use base64::Engine;
+
+    const SALT: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+
+    /// Signs RRsets as `zone` with the private half of `key`
+    enum Signer {
+        Rsa(RsaKeyPair),
+        Ecdsa(EcdsaKeyPair),
+        Ed25519(Ed25519KeyPair),
+    }
+
+    impl Signer {
+        fn new(algorithm: u8) -> (Self, Key) {
+            let (signer, public_key) = match algorithm {
+                8 => {
+                    let pair = RsaKeyPair::generate(KeySize::Rsa2048).unwrap();
+                    let components = PublicKeyComponents::<Vec<u8>>::from(pair.public_key());
+                    let mut public_key = vec![components.e.len() as u8];
+                    public_key.extend_from_slice(&components.e);
+                    public_key.extend_from_slice(&components.n);
+                    (Self::Rsa(pair), public_key)
+                }
+                13 => {
+                    let pair = EcdsaKeyPair::generate(&signature::ECDSA_P256_SHA256_FIXED_SIGNING)
+                        .unwrap();
+                    // Without the uncompressed point prefix (RFC 6605 4)
+                    let public_key = pair.public_key().as_ref()[1..].to_vec();
+                    (Self::Ecdsa(pair), public_key)
+                }
+                15 => {
+                    let pair = Ed25519KeyPair::generate().unwrap();
+                    let public_key = pair.public_key().as_ref().to_vec();
+                    (Self::Ed25519(pair), public_key)
+                }
+                _ => unreachable!(),
+            };
+
+            let dnskey = [&[1, 0, 3, algorithm][..], &public_key].concat();
+            let key = Key {
+                tag: key_tag(&dnskey),
+                algorithm,
+                public_key,
+            };
+            (signer, key)
+        }
+
+        fn sign(&self, message: &[u8]) -> Vec<u8> {
+            let rng = SystemRandom::new();
+            match self {
+                Self::Rsa(pair) => {
+                    let mut sig = vec![0; pair.public_modulus_len()];
+                    pair.sign(&signature::RSA_PKCS1_SHA256, &rng, message, &mut sig)
+                        .unwrap();
+                    sig
+                }
+                Self::Ecdsa(pair) => pair.sign(&rng, message).unwrap().as_ref().to_vec(),
+                Self::Ed25519(pair) => pair.sign(message).as_ref().to_vec(),
+            }
+        }
+    }
+
+    fn now() -> u32 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32
+    }
+
+    /// RRSIG over the `rrtype` RRset of `name` in `records`, valid from `inception` to `expiration`
+    fn rrsig(
+        records: &[Record],
+        name: &str,
+        rrtype: u16,
+        labels: u8,
+        (signer, key): &(Signer, Key),
+        zone: &str,
+        (inception, expiration): (u32, u32),
+    ) -> Record {
+        let mut data = rrtype.to_be_bytes().to_vec();
+        data.extend_from_slice(&[key.algorithm, labels]);
+        data.extend_from_slice(&3600u32.to_be_bytes());
+        data.extend_from_slice(&expiration.to_be_bytes());
+        data.extend_from_slice(&inception.to_be_bytes());
+        data.extend_from_slice(&key.tag.to_be_bytes());
+        write_name(&mut data, zone).unwrap();
+
+        let signed =
+            signed_data(records, name, rrtype, labels as usize, &data[4..8], &data).unwrap();
+        data.extend_from_slice(&signer.sign(&signed));
+        Record::new(name, rtype::RRSIG, 3600, data)
+    }
+
+    fn a(name: &str, address: [u8; 4]) -> Record {
+        Record::new(name, rtype::A, 3600, address.to_vec())
+    }
+
+    /// Type bitmap of a single window (RFC 4034 4.1.2)
+    fn bitmap(types: &[u16]) -> Vec<u8> {
+        let mut block = vec![0; 32];
+        for t in types {
+            block[*t as usize / 8] |= 0x80 >> (*t % 8);
+        }
+        let len = block.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        block.truncate(len);
+
+        [vec![0, len as u8], block].concat()
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[u16]) -> Record {
+        let mut data = Vec::new();
+        write_name(&mut data, next).unwrap();
+        data.extend_from_slice(&bitmap(types));
+        Record::new(owner, rtype::NSEC, 3600, data)
+    }
+
+    /// NSEC3 chain over `names` in `zone`, with the types of each name
+    fn nsec3_chain(
+        zone: &str,
+        names: &[(&str, &[u16])],
+        iterations: u16,
+        opt_out: bool,
+    ) -> Vec<Record> {
+        let mut hashed: Vec<_> = names
+            .iter()
+            .map(|(name, types)| (nsec3_digest(name, &SALT, iterations).unwrap(), *types))
+            .collect();
+        hashed.sort();
+
+        (0..hashed.len())
+            .map(|i| {
+                let (hash, types) = &hashed[i];
+                let next = &hashed[(i + 1) % hashed.len()].0;
+                let mut data = vec![1, u8::from(opt_out)];
+                data.extend_from_slice(&iterations.to_be_bytes());
+                data.push(SALT.len() as u8);
+                data.extend_from_slice(&SALT);
+                data.push(next.len() as u8);
+                data.extend_from_slice(next);
+                data.extend_from_slice(&bitmap(types));
+                Record::new(
+                    &format!("{}.{}", base32hex(hash), zone),
+                    rtype::NSEC3,
+                    3600,
+                    data,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn key_tag_and_ds_digest_match_rfc_4034() {
+        // RFC 4034 5.4
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(concat!(
+                "AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZ",
+                "DRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9Xzc",
+                "nOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+            ))
+            .unwrap();
+        let dnskey = [&[1, 0, 3, 5][..], &key].concat();
+
+        assert_eq!(key_tag(&dnskey), 60485);
+        assert_eq!(
+            ds_digest("dskey.example.com", &dnskey, 1).unwrap(),
+            from_hex("2bb183af5f22588179a53b0a98631fad1a292118").unwrap()
+        );
+    }
+
+    #[test]
+    fn nsec3_hashes_match_rfc_5155() {
+        // RFC 5155 Appendix A
+        for (name, hash) in [
+            ("example", "0p9mhaveqvm6t7vbl5lop2u3t2rp3tom"),
+            ("a.example", "35mthgpgcu1qg68fab165klnsnk3dpvl"),
+            ("ai.example", "gjeqe526plbf1g8mklp59enfd789njgi"),
+            ("ns1.example", "2t7b4g4vsa5smi47k61mv5bv1a22bojr"),
+            ("ns2.example", "q04jkcevqvmu85r014c7dkba38o0ji5r"),
+            ("w.example", "k8udemvp1j2f7eg6jebps17vp3n8i58h"),
+            ("*.w.example", "r53bq7cc2uvmubfu5ocmm6pers9tk9en"),
+            ("x.w.example", "b4um86eghhds6nea196smvmlo4ors995"),
+            ("y.w.example", "ji6neoaepv8b5o6k4ev33abha8ht9fgc"),
+            ("x.y.w.example", "2vptu5timamqttgl4luu9kg21e0aor3s"),
+            ("xx.example", "t644ebqk9bibcna874givr6joj62mlhv"),
+        ] {
+            assert_eq!(nsec3_hash(name, &SALT, 12).unwrap(), hash, "{name}");
+        }
+    }
+
+    #[test]
+    fn base32hex_matches_rfc_4648() {
+        // RFC 4648 10, lowercased and without padding
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "co"),
+            ("fo", "cpng"),
+            ("foo", "cpnmu"),
+            ("foob", "cpnmuog"),
+            ("fooba", "cpnmuoj1"),
+            ("foobar", "cpnmuoj1e8"),
+        ] {
+            assert_eq!(base32hex(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn canonical_order_matches_rfc_4034() {
+        // RFC 4034 6.1, without the names needing escapes
+        let names = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "*.z.example",
+        ];
+        for pair in names.windows(2) {
+            assert_eq!(canonical_cmp(pair[0], pair[1]), Ordering::Less, "{pair:?}");
+        }
+        assert_eq!(canonical_cmp("Example.", "example"), Ordering::Equal);
+    }
+
+    #[test]
+    fn signed_rrsets_verify() {
+        let validity = (now() - 3600, now() + 3600);
+        for algorithm in [8, 13, 15] {
+            let signer = Signer::new(algorithm);
+            let keys = std::slice::from_ref(&signer.1);
+            let mut records = vec![
+                a("www.example", [192, 0, 2, 1]),
+                a("WWW.example", [192, 0, 2, 2]),
+            ];
+            records.push(rrsig(
+                &records,
+                "www.example",
+                rtype::A,
+                2,
+                &signer,
+                "example",
+                validity,
+            ));
+
+            assert!(verify_rrset(&records, "www.example", rtype::A, "example", keys).is_ok());
+            // Another signer name, or a record the signature does not cover
+            assert!(verify_rrset(&records, "www.example", rtype::A, "other", keys).is_err());
+            records.push(a("www.example", [192, 0, 2, 3]));
+            assert!(verify_rrset(&records, "www.example", rtype::A, "example", keys).is_err());
+        }
+    }
+
+    #[test]
+    fn rrsets_outside_the_validity_period_are_bogus() {
+        let signer = Signer::new(13);
+        let keys = std::slice::from_ref(&signer.1);
+        for validity in [(now() - 7200, now() - 3600), (now() + 3600, now() + 7200)] {
+            let mut records = vec![a("www.example", [192, 0, 2, 1])];
+            records.push(rrsig(
+                &records,
+                "www.example",
+                rtype::A,
+                2,
+                &signer,
+                "example",
+                validity,
+            ));
+
+            assert!(verify_rrset(&records, "www.example", rtype::A, "example", keys).is_err());
+        }
+    }
+
+    #[test]
+    fn wildcard_expansions_verify_as_the_wildcard() {
+        let signer = Signer::new(15);
+        let keys = std::slice::from_ref(&signer.1);
+        let validity = (now() - 3600, now() + 3600);
+        let mut records = vec![a("host.example", [192, 0, 2, 1])];
+        records.push(rrsig(
+            &records,
+            "host.example",
+            rtype::A,
+            1,
+            &signer,
+            "example",
+            validity,
+        ));
+
+        assert!(verify_rrset(&records, "host.example", rtype::A, "example", keys).is_ok());
+        assert_eq!(expansions(&records), vec![("host.example".to_string(), 1)]);
+    }
+
+    #[test]
+    fn nsec_proves_denials() {
+        let proof = [
+            nsec(
+                "example",
+                "a.example",
+                &[rtype::NS, rtype::SOA, rtype::NSEC],
+            ),
+            nsec("a.example", "c.example", &[rtype::A, rtype::NSEC]),
+        ];
+
+        // The wildcard *.example sorts between the apex and a.example
+        assert!(denies(&proof, "b.example", rtype::A, true).unwrap());
+        assert!(denies(&proof, "a.example", rtype::AAAA, false).unwrap());
+        // An NSEC listing the type, or a missing wildcard proof, is bogus
+        assert!(denies(&proof, "a.example", rtype::A, false).is_err());
+        assert!(denies(&proof[1..], "b.example", rtype::A, true).is_err());
+    }
+
+    #[test]
+    fn nsec3_proves_denials() {
+        let names: &[(&str, &[u16])] = &[
+            ("example", &[rtype::NS, rtype::SOA]),
+            ("a.example", &[rtype::A]),
+            ("b.example", &[rtype::A]),
+        ];
+        let proof = nsec3_chain("example", names, 12, false);
+
+        // Closest encloser, next closer and wildcard (RFC 5155 8.4)
+        assert!(denies(&proof, "q.example", rtype::A, true).unwrap());
+        assert!(denies(&proof, "a.example", rtype::AAAA, false).unwrap());
+        assert!(denies(&proof, "a.example", rtype::A, false).is_err());
+        assert!(proves_expansion(&proof, "q.example", 1).unwrap());
+    }
+
+    #[test]
+    fn nsec3_opt_out_is_insecure() {
+        let names: &[(&str, &[u16])] = &[
+            ("example", &[rtype::NS, rtype::SOA]),
+            ("a.example", &[rtype::A]),
+        ];
+        let proof = nsec3_chain("example", names, 0, true);
+
+        assert!(!denies(&proof, "q.example", rtype::A, true).unwrap());
+    }
+
+    #[test]
+    fn nsec3_with_too_many_iterations_is_insecure() {
+        let names: &[(&str, &[u16])] = &[
+            ("example", &[rtype::NS, rtype::SOA]),
+            ("a.example", &[rtype::A]),
+        ];
+        let proof = nsec3_chain("example", names, MAX_NSEC3_ITERATIONS + 1, false);
+
+        assert!(nsec3s(&proof).unwrap().is_none());
+        assert!(!denies(&proof, "q.example", rtype::A, true).unwrap());
+        assert!(!proves_expansion(&proof, "q.example", 1).unwrap());
+    }
+
+    #[test]
+    fn type_bitmaps_are_read_by_window() {
+        let bitmap = [bitmap(&[rtype::A, rtype::NS]), vec![1, 1, 0x40]].concat();
+
+        assert!(has_type(&bitmap, rtype::A));
+        assert!(has_type(&bitmap, rtype::NS));
+        assert!(!has_type(&bitmap, rtype::AAAA));
+        // Type 257 in window 1
+        assert!(has_type(&bitmap, 257));
+    }
+}
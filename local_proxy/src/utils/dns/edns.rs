@@ -0,0 +1,200 @@
+//! EDNS(0) handling for queries forwarded upstream.
+//!
+//! Queries are normalized so that per-client options do not fragment the cache:
+//! the OPT record is rebuilt with a fixed payload size, only the DO bit is kept,
+//! and EDNS Client Subnet is stripped, passed through or replaced as configured.
+
+use super::message::{rtype, Message, Record};
+use crate::{config::DnsConfig, Error, PROXY};
+
+use std::net::IpAddr;
+
+const UDP_PAYLOAD_SIZE: u16 = 1232;
+const OPTION_ECS: u16 = 8;
+const OPTION_PADDING: u16 = 12;
+/// RFC 8467 4.1: recommended block length for queries
+const PADDING_BLOCK: usize = 128;
+const DNSSEC_OK: u32 = 0x8000;
+pub const AUTHENTIC_DATA: u16 = 0x0020;
+
+/// What the client asked for, so that the shared cached response can be tailored back to it
+pub struct Client {
+    opt: bool,
+    dnssec_ok: bool,
+    authentic_data: bool,
+}
+
+pub enum Ecs {
+    Strip,
+    Passthrough,
+    Set(Vec<u8>),
+}
+
+impl Ecs {
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self, Error> {
+        let ecs = match config.and_then(|c| c.ecs.as_deref()) {
+            None | Some("strip") => return Ok(Self::Strip),
+            Some("passthrough") => return Ok(Self::Passthrough),
+            Some(s) => s,
+        };
+
+        let (addr, prefix) = match ecs.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, prefix.parse::<u8>()?),
+            None => {
+                let addr = ecs.parse::<IpAddr>()?;
+                (addr, if addr.is_ipv4() { 24 } else { 56 })
+            }
+        };
+        let (family, octets) = match addr {
+            IpAddr::V4(v4) => (1_u16, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2_u16, v6.octets().to_vec()),
+        };
+        if prefix as usize > octets.len() * 8 {
+            return Err("".into());
+        }
+
+        // RFC 7871 6: the address is truncated to the prefix and the remaining bits are zero
+        let len = (prefix as usize).div_ceil(8);
+        let mut address = octets[..len].to_vec();
+        if prefix % 8 != 0 {
+            if let Some(last) = address.last_mut() {
+                *last &= 0xff << (8 - prefix % 8);
+            }
+        }
+
+        let mut data = Vec::new();
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(prefix);
+        data.push(0);
+        data.extend_from_slice(&address);
+        Ok(Self::Set(data))
+    }
+}
+
+/// Rebuilds the OPT record of `query`, returning the new query and what the client asked for
+pub fn normalize(query: &[u8]) -> Result<(Vec<u8>, Client), Error> {
+    let proxy = PROXY.get().ok_or("")?;
+    let ecs = &proxy.ecs;
+    let dnssec = proxy
+        .config
+        .dns
+        .as_ref()
+        .and_then(|d| d.dnssec)
+        .unwrap_or(false);
+
+    let mut message = Message::parse(query)?;
+    let client_opt = message
+        .additionals
+        .iter()
+        .position(|r| r.rtype == rtype::OPT)
+        .map(|i| message.additionals.remove(i));
+
+    let client = Client {
+        opt: client_opt.is_some(),
+        dnssec_ok: client_opt.as_ref().is_some_and(|o| o.ttl & DNSSEC_OK != 0),
+        authentic_data: message.flags & AUTHENTIC_DATA != 0,
+    };
+
+    let mut options = Vec::new();
+    let mut flags = if dnssec { DNSSEC_OK } else { 0 };
+    if let Some(opt) = &client_opt {
+        flags |= opt.ttl & DNSSEC_OK;
+        if let Ecs::Passthrough = ecs {
+            options.extend(
+                parse_options(&opt.data)?
+                    .into_iter()
+                    .filter(|o| o.0 == OPTION_ECS),
+            );
+        }
+    }
+    if let Ecs::Set(data) = ecs {
+        options.push((OPTION_ECS, data.clone()));
+    }
+
+    if client_opt.is_some() || flags != 0 || !options.is_empty() {
+        message.additionals.push(opt_record(flags, &options)?);
+    }
+
+    Ok((message.to_vec()?, client))
+}
+
+/// Removes what the client did not ask for from a response to the normalized query:
+/// OPT (RFC 6891 7), DNSSEC records (RFC 4035 3.2.1) and AD (RFC 6840 5.8)
+pub fn restore(response: &[u8], client: &Client) -> Result<Vec<u8>, Error> {
+    let mut message = Message::parse(response)?;
+
+    if !client.opt {
+        message.additionals.retain(|r| r.rtype != rtype::OPT);
+    }
+    if !client.dnssec_ok {
+        let qtype = message.questions.first().map(|q| q.qtype);
+        let keep = |r: &Record| {
+            !matches!(r.rtype, rtype::RRSIG | rtype::NSEC | rtype::NSEC3) || Some(r.rtype) == qtype
+        };
+        message.answers.retain(keep);
+        message.authorities.retain(keep);
+        message.additionals.retain(keep);
+
+        if !client.authentic_data {
+            message.flags &= !AUTHENTIC_DATA;
+        }
+    }
+
+    message.to_vec()
+}
+
+/// Pads `query` to a multiple of 128 bytes (RFC 7830, RFC 8467)
+pub fn pad(query: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut message = Message::parse(query)?;
+    let index = match message
+        .additionals
+        .iter()
+        .position(|r| r.rtype == rtype::OPT)
+    {
+        Some(i) => i,
+        None => {
+            message.additionals.push(opt_record(0, &[])?);
+            message.additionals.len() - 1
+        }
+    };
+
+    let unpadded = message.to_vec()?.len() + 4;
+    let padding = unpadded.div_ceil(PADDING_BLOCK) * PADDING_BLOCK - unpadded;
+
+    let opt = &mut message.additionals[index];
+    opt.data.extend_from_slice(&OPTION_PADDING.to_be_bytes());
+    opt.data
+        .extend_from_slice(&u16::try_from(padding)?.to_be_bytes());
+    opt.data.resize(opt.data.len() + padding, 0);
+
+    message.to_vec()
+}
+
+fn opt_record(flags: u32, options: &[(u16, Vec<u8>)]) -> Result<Record, Error> {
+    let mut data = Vec::new();
+    for (code, value) in options {
+        data.extend_from_slice(&code.to_be_bytes());
+        data.extend_from_slice(&u16::try_from(value.len())?.to_be_bytes());
+        data.extend_from_slice(value);
+    }
+
+    Ok(Record {
+        name: String::new(),
+        rtype: rtype::OPT,
+        class: UDP_PAYLOAD_SIZE,
+        ttl: flags,
+        data,
+    })
+}
+
+fn parse_options(mut data: &[u8]) -> Result<Vec<(u16, Vec<u8>)>, Error> {
+    let mut options = Vec::new();
+    while !data.is_empty() {
+        let code = u16::from_be_bytes(data.get(0..2).ok_or("")?.try_into()?);
+        let len = u16::from_be_bytes(data.get(2..4).ok_or("")?.try_into()?) as usize;
+        options.push((code, data.get(4..(4 + len)).ok_or("")?.to_vec()));
+        data = &data[(4 + len)..];
+    }
+
+    Ok(options)
+}
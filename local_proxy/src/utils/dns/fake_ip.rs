@@ -0,0 +1,211 @@
+//! Fake-IP mode for transparent proxying.
+//!
+//! The DNS inbound answers A/AAAA queries with addresses from reserved pools, and the
+//! transparent proxy inbound maps them back to the queried domain.
+//! The mapping only lives in memory and is forgotten a day after it was last handed out,
+//! so clients should not keep fake answers across restarts.
+
+use super::message::{rtype, Message, Record, CLASS_IN};
+use crate::{config::DnsConfig, utils::SuffixMatcher, Error, PROXY};
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::Duration,
+};
+use ttl_cache::TtlCache;
+
+/// Short, so that clients ask again soon after the mapping is lost
+const TTL: u32 = 1;
+/// Mappings not handed out again for this long are forgotten
+const MAPPING_TTL: Duration = Duration::from_secs(86400);
+const MAX_MAPPINGS: usize = 65536;
+
+pub struct FakeIp {
+    v4: Option<Pool>,
+    v6: Option<Pool>,
+    /// Only these domains get fake addresses when set, and "exclude" never does
+    include: Option<SuffixMatcher>,
+    exclude: SuffixMatcher,
+    state: Mutex<State>,
+}
+
+struct Pool {
+    base: u128,
+    size: u128,
+    is_ipv6: bool,
+}
+
+struct State {
+    next_v4: u128,
+    next_v6: u128,
+    by_name: TtlCache<(String, bool), IpAddr>,
+    by_addr: TtlCache<IpAddr, String>,
+}
+
+impl FakeIp {
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self, Error> {
+        let mut fake_ip = Self {
+            v4: None,
+            v6: None,
+            include: None,
+            exclude: SuffixMatcher::default(),
+            state: Mutex::new(State {
+                next_v4: 0,
+                next_v6: 0,
+                by_name: TtlCache::new(MAX_MAPPINGS),
+                by_addr: TtlCache::new(MAX_MAPPINGS),
+            }),
+        };
+        let config = match config.and_then(|c| c.fake_ip.as_ref()) {
+            Some(c) => c,
+            None => return Ok(fake_ip),
+        };
+
+        fake_ip.v4 = Some(Pool::parse(
+            config.ipv4.as_deref().unwrap_or("198.18.0.0/15"),
+        )?);
+        fake_ip.v6 = Some(Pool::parse(
+            config.ipv6.as_deref().unwrap_or("fdfe:dcba:9876::/64"),
+        )?);
+        if fake_ip.v4.as_ref().is_some_and(|p| p.is_ipv6)
+            || fake_ip.v6.as_ref().is_some_and(|p| !p.is_ipv6)
+        {
+            return Err("".into());
+        }
+        fake_ip.include = config.include.as_ref().map(SuffixMatcher::from_domains);
+        fake_ip.exclude = SuffixMatcher::from_domains(config.exclude.iter().flatten());
+
+        Ok(fake_ip)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.v4.is_some() || self.v6.is_some()
+    }
+
+    fn fakes(&self, name: &str) -> bool {
+        !self.exclude.matches(name) && self.include.as_ref().is_none_or(|i| i.matches(name))
+    }
+
+    /// Whether `addr` is in one of the pools
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        [&self.v4, &self.v6]
+            .into_iter()
+            .flatten()
+            .any(|p| p.contains(addr))
+    }
+
+    /// Returns the domain a fake address was handed out for
+    pub fn lookup(&self, addr: &IpAddr) -> Option<String> {
+        self.state.lock().ok()?.by_addr.get(addr).cloned()
+    }
+
+    fn allocate(&self, name: &str, is_ipv6: bool) -> Result<IpAddr, Error> {
+        let pool = match if is_ipv6 { &self.v6 } else { &self.v4 } {
+            Some(p) => p,
+            None => return Err("".into()),
+        };
+        let mut state = self.state.lock().map_err(|_| "")?;
+
+        let key = (name.to_string(), is_ipv6);
+        if let Some(addr) = state.by_name.get(&key).copied() {
+            // Both caches are refreshed together, so that they expire and evict in the same order
+            if state.by_addr.get(&addr).is_some_and(|n| n == name) {
+                state.by_name.insert(key, addr, MAPPING_TTL);
+                state.by_addr.insert(addr, name.to_string(), MAPPING_TTL);
+                return Ok(addr);
+            }
+            state.by_name.remove(&key);
+        }
+
+        // Skips the network and broadcast addresses, and recycles the oldest ones when exhausted
+        let next = if is_ipv6 {
+            &mut state.next_v6
+        } else {
+            &mut state.next_v4
+        };
+        *next = if *next + 2 >= pool.size { 1 } else { *next + 1 };
+        let addr = pool.addr(*next);
+
+        if let Some(old) = state.by_addr.insert(addr, name.to_string(), MAPPING_TTL) {
+            state.by_name.remove(&(old, is_ipv6));
+        }
+        state.by_name.insert(key, addr, MAPPING_TTL);
+
+        Ok(addr)
+    }
+}
+
+impl Pool {
+    fn parse(cidr: &str) -> Result<Self, Error> {
+        let (addr, prefix) = cidr.split_once('/').ok_or("")?;
+        let addr: IpAddr = addr.parse()?;
+        let prefix: u32 = prefix.parse()?;
+
+        let (base, bits) = match addr {
+            IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+            IpAddr::V6(v6) => (u128::from(v6), 128),
+        };
+        if prefix > bits || !(2..128).contains(&(bits - prefix)) {
+            return Err("".into());
+        }
+        let host_bits = bits - prefix;
+
+        Ok(Self {
+            base: base & !((1_u128 << host_bits) - 1),
+            size: 1_u128 << host_bits,
+            is_ipv6: addr.is_ipv6(),
+        })
+    }
+
+    fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V4(v4) if !self.is_ipv6 => u32::from(*v4) as u128,
+            IpAddr::V6(v6) if self.is_ipv6 => u128::from(*v6),
+            _ => return false,
+        };
+        addr >= self.base && addr - self.base < self.size
+    }
+
+    fn addr(&self, offset: u128) -> IpAddr {
+        if self.is_ipv6 {
+            IpAddr::V6(Ipv6Addr::from(self.base + offset))
+        } else {
+            IpAddr::V4(Ipv4Addr::from((self.base + offset) as u32))
+        }
+    }
+}
+
+/// Builds a reply with a fake address, or returns `None` when `query` should be resolved normally
+pub fn answer(query: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let proxy = PROXY.get().ok_or("")?;
+    let fake_ip = &proxy.fake_ip;
+    if !fake_ip.is_enabled() {
+        return Ok(None);
+    }
+
+    let query = Message::parse(query)?;
+    let question = match query.questions.first() {
+        Some(q) if q.qclass == CLASS_IN && matches!(q.qtype, rtype::A | rtype::AAAA) => q,
+        _ => return Ok(None),
+    };
+    let name = question.name.trim_end_matches('.').to_ascii_lowercase();
+    if !name.contains('.')
+        || !fake_ip.fakes(&name)
+        || proxy.hosts.contains(&name)
+        || proxy.blocklist.is_blocked(&name)
+    {
+        return Ok(None);
+    }
+
+    let data = match fake_ip.allocate(&name, question.qtype == rtype::AAAA)? {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    };
+    let mut response = Message::reply(&query);
+    response
+        .answers
+        .push(Record::new(&question.name, question.qtype, TTL, data));
+
+    Ok(Some(response.to_vec()?))
+}
@@ -0,0 +1,200 @@
+//! Local overrides answered without asking the upstream resolver.
+
+use super::{
+    message::{rtype, Message, Record},
+    nested_query,
+};
+use crate::{config::DnsConfig, utils::SuffixMatcher, Error, PROXY};
+
+use regex::Regex;
+use std::{collections::HashMap, io::Read, net::IpAddr};
+
+const TTL: u32 = 60;
+
+pub struct Hosts {
+    names: HashMap<String, Vec<IpAddr>>,
+    rewrites: Vec<Rewrite>,
+}
+
+struct Rewrite {
+    matcher: Matcher,
+    target: Target,
+}
+
+enum Matcher {
+    Exact(String),
+    Suffix(SuffixMatcher),
+    Regex(Regex),
+}
+
+enum Target {
+    Addr(IpAddr),
+    Cname(String),
+}
+
+enum Answer {
+    Addrs(Vec<IpAddr>),
+    Cname(String),
+}
+
+impl Hosts {
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self, Error> {
+        let mut hosts = Self {
+            names: HashMap::new(),
+            rewrites: Vec::new(),
+        };
+        let config = match config {
+            Some(c) => c,
+            None => return Ok(hosts),
+        };
+
+        if let Some(path) = &config.hosts_file {
+            let mut file = String::new();
+            std::fs::File::open(path)?.read_to_string(&mut file)?;
+
+            for line in file.lines() {
+                let line = line.split('#').next().unwrap_or("");
+                let mut fields = line.split_whitespace();
+                let addr = match fields.next().map(|a| a.parse()) {
+                    Some(Ok(a)) => a,
+                    _ => continue,
+                };
+                for name in fields {
+                    hosts.names.entry(normalize(name)).or_default().push(addr);
+                }
+            }
+        }
+
+        if let Some(names) = &config.hosts {
+            for (name, addrs) in names {
+                hosts
+                    .names
+                    .entry(normalize(name))
+                    .or_default()
+                    .extend(addrs);
+            }
+        }
+
+        for rewrite in config.rewrites.iter().flatten() {
+            let matcher = match rewrite.match_type.as_deref() {
+                None | Some("exact") => Matcher::Exact(normalize(&rewrite.domain)),
+                Some("suffix") => Matcher::Suffix(SuffixMatcher::from_domains([&rewrite.domain])),
+                Some("regex") => Matcher::Regex(Regex::new(&rewrite.domain)?),
+                _ => return Err("".into()),
+            };
+            let target = match rewrite.record_type.as_str() {
+                "A" => Target::Addr(IpAddr::V4(rewrite.value.parse()?)),
+                "AAAA" => Target::Addr(IpAddr::V6(rewrite.value.parse()?)),
+                "CNAME" => Target::Cname(normalize(&rewrite.value)),
+                _ => return Err("".into()),
+            };
+
+            hosts.rewrites.push(Rewrite { matcher, target });
+        }
+
+        Ok(hosts)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty() && self.rewrites.is_empty()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.lookup(name).is_some()
+    }
+
+    /// Returns the addresses or the alias configured for `name`
+    fn lookup(&self, name: &str) -> Option<Answer> {
+        let name = normalize(name);
+        if let Some(addrs) = self.names.get(&name) {
+            return Some(Answer::Addrs(addrs.clone()));
+        }
+
+        let mut matched = self.rewrites.iter().filter(|r| r.matcher.is_match(&name));
+        match &matched.next()?.target {
+            Target::Cname(target) => Some(Answer::Cname(target.clone())),
+            Target::Addr(addr) => {
+                let mut addrs = vec![*addr];
+                addrs.extend(matched.filter_map(|r| match r.target {
+                    Target::Addr(addr) => Some(addr),
+                    Target::Cname(_) => None,
+                }));
+                Some(Answer::Addrs(addrs))
+            }
+        }
+    }
+}
+
+impl Matcher {
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Self::Exact(domain) => name == domain,
+            Self::Suffix(suffix) => suffix.matches(name),
+            Self::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+/// Builds a reply from the local overrides, or returns `None` when `query` is not overridden
+pub async fn answer(query: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let hosts = &PROXY.get().ok_or("")?.hosts;
+    if hosts.is_empty() {
+        return Ok(None);
+    }
+
+    let query = Message::parse(query)?;
+    let question = match query.questions.first() {
+        Some(q) => q,
+        None => return Ok(None),
+    };
+    if hosts.lookup(&question.name).is_none() {
+        return Ok(None);
+    }
+
+    let mut response = Message::reply(&query);
+    let mut name = question.name.clone();
+    // Follows at most 8 locally configured aliases so that loops terminate
+    for _ in 0..8 {
+        match hosts.lookup(&name) {
+            Some(Answer::Cname(target)) => {
+                response.answers.push(Record::cname(&name, TTL, &target)?);
+                name = target;
+            }
+            Some(Answer::Addrs(addrs)) => {
+                for addr in addrs {
+                    match (addr, question.qtype) {
+                        (IpAddr::V4(v4), rtype::A) => response.answers.push(Record::new(
+                            &name,
+                            rtype::A,
+                            TTL,
+                            v4.octets().to_vec(),
+                        )),
+                        (IpAddr::V6(v6), rtype::AAAA) => response.answers.push(Record::new(
+                            &name,
+                            rtype::AAAA,
+                            TTL,
+                            v6.octets().to_vec(),
+                        )),
+                        _ => {}
+                    }
+                }
+                break;
+            }
+            None => {
+                let upstream = Message::query(query.id, &name, question.qtype).to_vec()?;
+                if let Ok(upstream) = Box::pin(nested_query(upstream)).await {
+                    let upstream = Message::parse(&upstream)?;
+                    response.set_rcode(upstream.rcode());
+                    response.answers.extend(upstream.answers);
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(Some(response.to_vec()?))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,247 @@
+//! DNS over HTTPS.
+//!
+//! Queries are multiplexed over a long-lived HTTP/2 connection per endpoint.
+//! Endpoints that do not negotiate `h2` fall back to one HTTP/1.1 request per query.
+
+use super::request_config;
+use crate::{
+    config::DoHConfig,
+    inbound::http::http_proxy,
+    outbound::{layer::TlsClient, proxy_stack},
+    utils::{Body, HostName, SocketAddr},
+    Connection, Error,
+};
+
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{client::conn::http2, header::HeaderName, Method, Request, Uri};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+type H2Sender = http2::SendRequest<Full<Bytes>>;
+
+enum Pooled {
+    H2 {
+        sender: H2Sender,
+        last_used: Instant,
+        id: u64,
+    },
+    /// `h2` is tried again after `HTTP1_TTL`
+    Http1 { since: Instant },
+}
+
+const HTTP1_TTL: Duration = Duration::from_secs(600);
+/// For connecting and both handshakes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static POOL: Lazy<Mutex<HashMap<String, Pooled>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+pub async fn query(query: &[u8], doh_config: &DoHConfig, endpoint: &Uri) -> Result<Vec<u8>, Error> {
+    let mut sender = match pooled_sender(doh_config, endpoint, false).await? {
+        Some(s) => s,
+        None => return http1_query(query, doh_config, endpoint).await,
+    };
+
+    let response = match sender
+        .send_request(build_request(query, doh_config, endpoint)?)
+        .await
+    {
+        Ok(o) => o,
+        Err(_) => {
+            let mut sender = match pooled_sender(doh_config, endpoint, true).await? {
+                Some(s) => s,
+                None => return http1_query(query, doh_config, endpoint).await,
+            };
+            sender
+                .send_request(build_request(query, doh_config, endpoint)?)
+                .await?
+        }
+    };
+    if !response.status().is_success() {
+        return Err("".into());
+    }
+
+    Ok(response.into_body().collect().await?.to_bytes().to_vec())
+}
+
+async fn http1_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let request = build_request(query, doh_config, endpoint)?.map(Body::new);
+
+    let response = http_proxy::send_request(request, &request_config(doh_config)).await?;
+    if !response.status().is_success() {
+        return Err("".into());
+    }
+
+    Ok(response.into_body().collect().await?.to_bytes().to_vec())
+}
+
+pub(super) fn build_request(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Request<Full<Bytes>>, Error> {
+    let method = match doh_config.method.as_deref() {
+        None => Method::POST,
+        Some(m) if m.eq_ignore_ascii_case("POST") => Method::POST,
+        Some(m) if m.eq_ignore_ascii_case("GET") => Method::GET,
+        _ => return Err("".into()),
+    };
+
+    let mut request = Request::builder().header("accept", "application/dns-message");
+    let body;
+    if method == Method::GET {
+        // RFC 8484 4.1: the ID should be 0 so that HTTP caches can share responses
+        let mut query = query.to_vec();
+        *query.get_mut(0).ok_or("")? = 0;
+        *query.get_mut(1).ok_or("")? = 0;
+        let base64 = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+        let mut path_and_query = endpoint.path().to_string();
+        path_and_query.push('?');
+        if let Some(q) = endpoint.query() {
+            path_and_query.push_str(q);
+            path_and_query.push('&');
+        }
+        path_and_query.push_str("dns=");
+        path_and_query.push_str(&base64.encode(query));
+
+        let mut uri = endpoint.clone().into_parts();
+        uri.path_and_query = Some(path_and_query.parse()?);
+        request = request.method(Method::GET).uri(Uri::from_parts(uri)?);
+        body = Bytes::new();
+    } else {
+        request = request
+            .method(Method::POST)
+            .uri(endpoint)
+            .header("content-type", "application/dns-message");
+        body = Bytes::copy_from_slice(query);
+    }
+
+    if let Some(user_agent) = &doh_config.user_agent {
+        request = request.header("user-agent", user_agent);
+    }
+    let mut request = request.body(Full::new(body))?;
+    if let Some(headers) = &doh_config.headers {
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .insert(HeaderName::from_str(name)?, value.parse()?);
+        }
+    }
+
+    Ok(request)
+}
+
+/// Returns `None` when the endpoint only speaks HTTP/1.1
+async fn pooled_sender(
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+    reconnect: bool,
+) -> Result<Option<H2Sender>, Error> {
+    // Not locked while connecting, so that a stalled endpoint does not hold up the others
+    {
+        let mut pool = POOL.lock().await;
+        if !reconnect {
+            match pool.get_mut(&doh_config.endpoint) {
+                Some(Pooled::Http1 { since }) if since.elapsed() < HTTP1_TTL => return Ok(None),
+                Some(Pooled::H2 {
+                    sender, last_used, ..
+                }) if !sender.is_closed() => {
+                    *last_used = Instant::now();
+                    return Ok(Some(sender.clone()));
+                }
+                _ => {}
+            }
+        }
+        pool.remove(&doh_config.endpoint);
+    }
+
+    let sender = tokio::time::timeout(CONNECT_TIMEOUT, async {
+        let (server, is_h2) = connect(doh_config, endpoint).await?;
+        if !is_h2 {
+            return Ok::<_, Error>(None);
+        }
+
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(server)).await?;
+        tokio::spawn(conn);
+        Ok(Some(sender))
+    })
+    .await??;
+
+    let mut pool = POOL.lock().await;
+    let sender = match sender {
+        Some(sender) => sender,
+        None => {
+            let since = Instant::now();
+            pool.insert(doh_config.endpoint.clone(), Pooled::Http1 { since });
+            return Ok(None);
+        }
+    };
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let idle_timeout = Duration::from_secs(doh_config.idle_timeout.unwrap_or(60));
+    tokio::spawn(reap_idle(doh_config.endpoint.clone(), id, idle_timeout));
+
+    pool.insert(
+        doh_config.endpoint.clone(),
+        Pooled::H2 {
+            sender: sender.clone(),
+            last_used: Instant::now(),
+            id,
+        },
+    );
+
+    Ok(Some(sender))
+}
+
+async fn connect(doh_config: &DoHConfig, endpoint: &Uri) -> Result<(Connection, bool), Error> {
+    let req_conf = request_config(doh_config);
+
+    let hostname = HostName::from_str(endpoint.host().ok_or("")?)?;
+    let port = endpoint.port_u16().unwrap_or(443);
+    let addr = SocketAddr::new(hostname, port);
+    let fake_addr = SocketAddr::new(
+        req_conf.fake_host.clone().unwrap_or(addr.hostname.clone()),
+        addr.port,
+    );
+
+    TlsClient::with_cached_ech(&addr)
+        .wrap_connecting(&addr, true, || async {
+            let mut proxies = proxy_stack(&req_conf)?;
+            proxies.next().ok_or("")?.connect(proxies, &fake_addr).await
+        })
+        .await
+}
+
+/// Drops the pooled connection once nobody has used it for `idle_timeout`
+async fn reap_idle(endpoint: String, id: u64, idle_timeout: Duration) {
+    loop {
+        tokio::time::sleep(idle_timeout).await;
+
+        let mut pool = POOL.lock().await;
+        match pool.get(&endpoint) {
+            Some(Pooled::H2 {
+                last_used, id: id_, ..
+            }) if *id_ == id => {
+                if last_used.elapsed() >= idle_timeout {
+                    pool.remove(&endpoint);
+                    return;
+                }
+            }
+            _ => return,
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! Query log in JSON lines and resolver statistics.
+
+use super::message::Message;
+use crate::{config::DnsConfig, Error};
+
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+/// Lines beyond this many waiting to be written are dropped
+const QUEUE_LEN: usize = 4096;
+
+pub struct QueryLog {
+    writer: Option<mpsc::Sender<String>>,
+    stats: Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    queries: u64,
+    cache_hits: u64,
+    errors: u64,
+    upstreams: HashMap<String, UpstreamCounters>,
+}
+
+#[derive(Default, Serialize)]
+struct UpstreamCounters {
+    queries: u64,
+    errors: u64,
+    total_latency_ms: u64,
+}
+
+/// What happened to one query, filled in while it is resolved
+#[derive(Default)]
+pub struct Entry {
+    pub answered_by: &'static str,
+    pub upstream: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: u64,
+    client: Option<String>,
+    qname: String,
+    qtype: u16,
+    rcode: Option<u8>,
+    answered_by: &'a str,
+    cache: &'a str,
+    upstream: Option<&'a str>,
+    latency_ms: f64,
+    error: bool,
+}
+
+#[derive(Serialize)]
+struct Stats<'a> {
+    queries: u64,
+    cache_hits: u64,
+    hit_ratio: f64,
+    errors: u64,
+    upstreams: &'a HashMap<String, UpstreamCounters>,
+}
+
+impl QueryLog {
+    pub fn new(config: Option<&DnsConfig>) -> Result<Self, Error> {
+        let writer = match config.and_then(|c| c.query_log.as_ref()) {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+                tokio::spawn(write_lines(File::from_std(file), receiver));
+                Some(sender)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            writer,
+            stats: Mutex::new(Counters::default()),
+        })
+    }
+
+    pub fn record(
+        &self,
+        query: &[u8],
+        client: Option<SocketAddr>,
+        entry: &Entry,
+        result: &Result<Vec<u8>, Error>,
+        latency: Duration,
+    ) {
+        let cache_hit = entry.answered_by == "cache";
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.queries += 1;
+            stats.cache_hits += cache_hit as u64;
+            stats.errors += result.is_err() as u64;
+            if let (Some(upstream), false) = (&entry.upstream, cache_hit) {
+                let upstream = stats.upstreams.entry(upstream.clone()).or_default();
+                upstream.queries += 1;
+                upstream.errors += result.is_err() as u64;
+                upstream.total_latency_ms += latency.as_millis() as u64;
+            }
+        }
+
+        let writer = match &self.writer {
+            Some(w) => w,
+            None => return,
+        };
+        let question = Message::parse(query)
+            .ok()
+            .and_then(|m| m.questions.into_iter().next());
+        let line = Line {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            client: client.map(|c| c.to_string()),
+            // Labels may hold any byte, and control characters are not valid in JSON strings
+            qname: question
+                .as_ref()
+                .map(|q| q.name.replace(|c: char| c.is_control(), "\u{fffd}"))
+                .unwrap_or_default(),
+            qtype: question.as_ref().map(|q| q.qtype).unwrap_or(0),
+            rcode: result
+                .as_ref()
+                .ok()
+                .and_then(|r| r.get(3))
+                .map(|b| b & 0x0f),
+            answered_by: entry.answered_by,
+            cache: if cache_hit { "hit" } else { "miss" },
+            upstream: entry.upstream.as_deref(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            error: result.is_err(),
+        };
+
+        if let Ok(line) = json5::to_string(&line) {
+            let _ = writer.try_send(line + "\n");
+        }
+    }
+
+    /// Counters as a JSON object
+    pub fn stats(&self) -> Result<String, Error> {
+        let stats = self.stats.lock().map_err(|_| "")?;
+        let hit_ratio = if stats.queries == 0 {
+            0.0
+        } else {
+            stats.cache_hits as f64 / stats.queries as f64
+        };
+
+        Ok(json5::to_string(&Stats {
+            queries: stats.queries,
+            cache_hits: stats.cache_hits,
+            hit_ratio,
+            errors: stats.errors,
+            upstreams: &stats.upstreams,
+        })?)
+    }
+}
+
+/// Writes the lines sent by `QueryLog::record`, so that queries never wait for the disk
+async fn write_lines(file: File, mut lines: mpsc::Receiver<String>) {
+    let mut writer = BufWriter::new(file);
+    while let Some(line) = lines.recv().await {
+        if writer.write_all(line.as_bytes()).await.is_err() {
+            continue;
+        }
+        if lines.is_empty() {
+            let _ = writer.flush().await;
+        }
+    }
+}
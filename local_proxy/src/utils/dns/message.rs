@@ -0,0 +1,295 @@
+//! Minimal DNS wire format reader and writer.
+//!
+//! Names inside well-known RDATA are decompressed while parsing, so records can be
+//! re-encoded into a different message. Encoding never compresses.
+
+use crate::Error;
+
+pub mod rtype {
+    pub const A: u16 = 1;
+    pub const NS: u16 = 2;
+    pub const CNAME: u16 = 5;
+    pub const SOA: u16 = 6;
+    pub const PTR: u16 = 12;
+    pub const MX: u16 = 15;
+    pub const AAAA: u16 = 28;
+    pub const SRV: u16 = 33;
+    pub const DNAME: u16 = 39;
+    pub const OPT: u16 = 41;
+    pub const DS: u16 = 43;
+    pub const RRSIG: u16 = 46;
+    pub const NSEC: u16 = 47;
+    pub const DNSKEY: u16 = 48;
+    pub const NSEC3: u16 = 50;
+    pub const HTTPS: u16 = 65;
+}
+
+pub mod rcode {
+    pub const NOERROR: u8 = 0;
+    pub const SERVFAIL: u8 = 2;
+    pub const NXDOMAIN: u8 = 3;
+}
+
+pub const CLASS_IN: u16 = 1;
+
+#[derive(Clone)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+#[derive(Clone)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+#[derive(Clone)]
+pub struct Record {
+    pub name: String,
+    pub rtype: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Vec<u8>,
+}
+
+impl Message {
+    pub fn query(id: u16, name: &str, qtype: u16) -> Self {
+        Self {
+            id,
+            flags: 0x0100,
+            questions: vec![Question {
+                name: name.to_string(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    /// Empty response to `query` with QR, RA and the query's RD set
+    pub fn reply(query: &Self) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8080 | (query.flags & 0x0100),
+            questions: query.questions.clone(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000f) as u8
+    }
+
+    pub fn set_rcode(&mut self, rcode: u8) {
+        self.flags = (self.flags & !0x000f) | (rcode as u16 & 0x000f);
+    }
+
+    pub fn opt(&self) -> Option<&Record> {
+        self.additionals.iter().find(|r| r.rtype == rtype::OPT)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self, Error> {
+        let header = |i: usize| -> Result<u16, Error> {
+            Ok(u16::from_be_bytes(
+                buf.get(i..(i + 2)).ok_or("")?.try_into()?,
+            ))
+        };
+        let mut message = Self {
+            id: header(0)?,
+            flags: header(2)?,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        let counts = [header(4)?, header(6)?, header(8)?, header(10)?];
+
+        let mut pos = 12;
+        for _ in 0..counts[0] {
+            let (name, next) = read_name(buf, pos)?;
+            let fixed = buf.get(next..(next + 4)).ok_or("")?;
+            message.questions.push(Question {
+                name,
+                qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            });
+            pos = next + 4;
+        }
+
+        for (i, count) in counts[1..].iter().enumerate() {
+            for _ in 0..*count {
+                let (record, next) = Record::parse(buf, pos)?;
+                pos = next;
+                match i {
+                    0 => message.answers.push(record),
+                    1 => message.authorities.push(record),
+                    _ => message.additionals.push(record),
+                }
+            }
+        }
+
+        Ok(message)
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::with_capacity(512);
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            buf.extend_from_slice(&u16::try_from(count)?.to_be_bytes());
+        }
+
+        for question in &self.questions {
+            write_name(&mut buf, &question.name)?;
+            buf.extend_from_slice(&question.qtype.to_be_bytes());
+            buf.extend_from_slice(&question.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            record.write(&mut buf)?;
+        }
+
+        Ok(buf)
+    }
+}
+
+impl Record {
+    pub fn new(name: &str, rtype: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            rtype,
+            class: CLASS_IN,
+            ttl,
+            data,
+        }
+    }
+
+    pub fn cname(name: &str, ttl: u32, target: &str) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        write_name(&mut data, target)?;
+        Ok(Self::new(name, rtype::CNAME, ttl, data))
+    }
+
+    fn parse(buf: &[u8], pos: usize) -> Result<(Self, usize), Error> {
+        let (name, pos) = read_name(buf, pos)?;
+        let fixed = buf.get(pos..(pos + 10)).ok_or("")?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+        let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let start = pos + 10;
+        let end = start + len;
+        let raw = buf.get(start..end).ok_or("")?;
+
+        let mut data = Vec::with_capacity(len);
+        match rtype {
+            rtype::NS | rtype::CNAME | rtype::PTR | rtype::DNAME => {
+                write_name(&mut data, &read_name(buf, start)?.0)?;
+            }
+            rtype::MX | rtype::SRV => {
+                let skip = if rtype == rtype::MX { 2 } else { 6 };
+                data.extend_from_slice(raw.get(..skip).ok_or("")?);
+                write_name(&mut data, &read_name(buf, start + skip)?.0)?;
+            }
+            rtype::SOA => {
+                let (mname, next) = read_name(buf, start)?;
+                let (rname, next) = read_name(buf, next)?;
+                write_name(&mut data, &mname)?;
+                write_name(&mut data, &rname)?;
+                data.extend_from_slice(buf.get(next..end).ok_or("")?);
+            }
+            _ => data.extend_from_slice(raw),
+        }
+
+        Ok((
+            Self {
+                name,
+                rtype,
+                class,
+                ttl,
+                data,
+            },
+            end,
+        ))
+    }
+
+    fn write(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        write_name(buf, &self.name)?;
+        buf.extend_from_slice(&self.rtype.to_be_bytes());
+        buf.extend_from_slice(&self.class.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+        buf.extend_from_slice(&u16::try_from(self.data.len())?.to_be_bytes());
+        buf.extend_from_slice(&self.data);
+        Ok(())
+    }
+}
+
+/// Reads a possibly compressed name, returning it in dotted form without the trailing dot
+pub fn read_name(buf: &[u8], mut pos: usize) -> Result<(String, usize), Error> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *buf.get(pos).ok_or("")? as usize;
+        if len & 0xc0 == 0xc0 {
+            jumps += 1;
+            if jumps > 64 {
+                return Err("".into());
+            }
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3f) << 8) | *buf.get(pos + 1).ok_or("")? as usize;
+            continue;
+        } else if len & 0xc0 != 0 {
+            return Err("".into());
+        }
+
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+
+        let label = buf.get(pos..(pos + len)).ok_or("")?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(&String::from_utf8_lossy(label));
+        pos += len;
+    }
+
+    Ok((name, end.unwrap_or(pos)))
+}
+
+pub fn write_name(buf: &mut Vec<u8>, name: &str) -> Result<(), Error> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() {
+            continue;
+        }
+        if label.len() > 63 {
+            return Err("".into());
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    Ok(())
+}
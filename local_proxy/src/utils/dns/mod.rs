@@ -0,0 +1,295 @@
+mod blocklist;
+mod classic;
+mod dnssec;
+mod edns;
+mod fake_ip;
+mod hosts;
+mod https;
+mod log;
+mod message;
+#[cfg(feature = "quic")]
+mod quic;
+mod svcb;
+
+pub use blocklist::Blocklist;
+pub use edns::Ecs;
+pub use fake_ip::FakeIp;
+pub use hosts::Hosts;
+pub use log::QueryLog;
+pub use svcb::ech_config_list;
+
+use crate::{
+    config::DoHConfig,
+    inbound::http::http_proxy::RequestConfig,
+    utils::{HostName, SuffixMatcher},
+    Error, PROXY,
+};
+
+use dns_parser::QueryType;
+use hyper::Uri;
+use message::Message;
+use once_cell::sync::Lazy;
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Answers a query from a DNS inbound, which unlike this app's own lookups may get fake IPs
+pub async fn serve_query(query: Vec<u8>, client: SocketAddr) -> Result<Vec<u8>, Error> {
+    resolve(query, Some(client)).await
+}
+
+pub async fn doh_query(query: Vec<u8>) -> Result<Vec<u8>, Error> {
+    resolve(query, None).await
+}
+
+/// Drops the cached answer to `query`, so that the next lookup asks the upstream
+async fn uncache(query: &[u8]) -> Result<(), Error> {
+    let mut query = query.to_vec();
+    *query.get_mut(0).ok_or("")? = 0xab;
+    *query.get_mut(1).ok_or("")? = 0xcd;
+    let (query, _) = edns::normalize(&query)?;
+    PROXY
+        .get()
+        .ok_or("")?
+        .dns_cache
+        .write()
+        .await
+        .remove(&query);
+    Ok(())
+}
+
+/// Resolves a query made while answering another one, which is logged and counted only once
+async fn nested_query(query: Vec<u8>) -> Result<Vec<u8>, Error> {
+    lookup(query, false, &mut log::Entry::default()).await
+}
+
+async fn resolve(query: Vec<u8>, client: Option<SocketAddr>) -> Result<Vec<u8>, Error> {
+    let start = Instant::now();
+    let mut entry = log::Entry::default();
+    let result = lookup(query.clone(), client.is_some(), &mut entry).await;
+
+    PROXY
+        .get()
+        .ok_or("")?
+        .query_log
+        .record(&query, client, &entry, &result, start.elapsed());
+    result
+}
+
+async fn lookup(
+    mut query: Vec<u8>,
+    inbound: bool,
+    entry: &mut log::Entry,
+) -> Result<Vec<u8>, Error> {
+    if inbound {
+        if let Some(response) = fake_ip::answer(&query)? {
+            entry.answered_by = "fake_ip";
+            return Ok(response);
+        }
+    }
+
+    let id = (*query.first().ok_or("")?, *query.get(1).ok_or("")?);
+    *query.get_mut(0).ok_or("")? = 0xab;
+    *query.get_mut(1).ok_or("")? = 0xcd;
+
+    if let Some(mut response) = hosts::answer(&query).await? {
+        entry.answered_by = "hosts";
+        *response.get_mut(0).ok_or("")? = id.0;
+        *response.get_mut(1).ok_or("")? = id.1;
+
+        return Ok(response);
+    }
+    if let Some(mut response) = blocklist::answer(&query)? {
+        entry.answered_by = "blocklist";
+        *response.get_mut(0).ok_or("")? = id.0;
+        *response.get_mut(1).ok_or("")? = id.1;
+
+        return Ok(response);
+    }
+
+    let (query, client) = edns::normalize(&query)?;
+    let proxy = PROXY.get().ok_or("")?;
+    if let Some(s) = proxy.dns_cache.read().await.get(&query) {
+        entry.answered_by = "cache";
+        let mut result = edns::restore(s, &client)?;
+        *result.get_mut(0).ok_or("")? = id.0;
+        *result.get_mut(1).ok_or("")? = id.1;
+
+        return Ok(result);
+    }
+
+    let message = Message::parse(&query)?;
+    let qname = &message.questions.first().ok_or("")?.name;
+    entry.answered_by = "upstream";
+    entry.upstream = upstream(qname).map(|u| u.endpoint.clone());
+    let mut response_body = forward(&query).await?;
+
+    // Split DNS upstreams are usually intranet resolvers, so only the default one is validated
+    let dnssec = proxy.config.dns.as_ref().and_then(|d| d.dnssec);
+    if dnssec.unwrap_or(false)
+        && std::ptr::eq(
+            upstream(qname).ok_or("")?,
+            proxy.config.doh.as_ref().ok_or("")?,
+        )
+    {
+        response_body = match dnssec::validate(&message, &response_body).await {
+            Ok(o) => o,
+            Err(_) => {
+                entry.answered_by = "dnssec";
+                let mut response = Message::reply(&message);
+                response.set_rcode(message::rcode::SERVFAIL);
+                let mut response = edns::restore(&response.to_vec()?, &client)?;
+                *response.get_mut(0).ok_or("")? = id.0;
+                *response.get_mut(1).ok_or("")? = id.1;
+
+                return Ok(response);
+            }
+        };
+    }
+
+    proxy
+        .dns_cache
+        .write()
+        .await
+        .insert(query, response_body.clone(), Duration::from_secs(3600));
+
+    let mut response_body = edns::restore(&response_body, &client)?;
+    *response_body.get_mut(0).ok_or("")? = id.0;
+    *response_body.get_mut(1).ok_or("")? = id.1;
+
+    Ok(response_body)
+}
+
+/// Sends a normalized query to the upstream selected for its name
+async fn forward(query: &[u8]) -> Result<Vec<u8>, Error> {
+    let proxy = PROXY.get().ok_or("")?;
+    let message = Message::parse(query)?;
+    let doh_config = upstream(&message.questions.first().ok_or("")?.name).ok_or("")?;
+    let endpoint = Uri::from_str(&doh_config.endpoint)?;
+
+    let encrypted = !matches!(endpoint.scheme_str(), Some("udp") | Some("tcp"));
+    let padding = proxy
+        .config
+        .dns
+        .as_ref()
+        .and_then(|d| d.padding)
+        .unwrap_or(true);
+    let wire = if encrypted && padding {
+        edns::pad(query)?
+    } else {
+        query.to_vec()
+    };
+
+    Ok(match endpoint.scheme_str() {
+        Some("https") => https::query(&wire, doh_config, &endpoint).await?,
+        Some("tls") => classic::tls_query(&wire, doh_config, &endpoint).await?,
+        Some("tcp") => classic::tcp_query(&wire, doh_config, &endpoint).await?,
+        Some("udp") => classic::udp_query(&wire, doh_config, &endpoint).await?,
+        #[cfg(feature = "quic")]
+        Some("quic") => quic::doq_query(&wire, doh_config, &endpoint).await?,
+        #[cfg(feature = "quic")]
+        Some("h3") => quic::doh3_query(&wire, doh_config, &endpoint).await?,
+        _ => return Err("".into()),
+    })
+}
+
+/// Picks the split DNS upstream with the longest matching suffix, or the default one
+fn upstream(name: &str) -> Option<&'static DoHConfig> {
+    static SPLITS: Lazy<SuffixMatcher<&'static DoHConfig>> = Lazy::new(|| {
+        let dns = PROXY.get().and_then(|p| p.config.dns.as_ref());
+        let splits = dns.and_then(|d| d.split.as_ref());
+        SuffixMatcher::new(splits.into_iter().flatten().flat_map(|split| {
+            split
+                .domains
+                .iter()
+                .map(move |domain| (domain, &split.upstream))
+        }))
+    });
+
+    match SPLITS.get(name) {
+        Some(split) => Some(split),
+        None => PROXY.get()?.config.doh.as_ref(),
+    }
+}
+
+/// Addresses of an upstream connected to directly, from "bootstrap" or the other upstreams
+/// rather than the system resolver
+async fn upstream_addrs(
+    doh_config: &DoHConfig,
+    host: &str,
+    port: u16,
+) -> Result<Vec<SocketAddr>, Error> {
+    let hostname = HostName::from_str(host)?;
+    let name = match &hostname {
+        HostName::V4(ip) => return Ok(vec![SocketAddr::new((*ip).into(), port)]),
+        HostName::V6(ip) => return Ok(vec![SocketAddr::new((*ip).into(), port)]),
+        HostName::Domain(name) => name,
+    };
+    if let Some(bootstrap) = &doh_config.bootstrap {
+        return Ok(bootstrap
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect());
+    }
+    // Its own name would be looked up through itself
+    if upstream(name).is_some_and(|u| std::ptr::eq(u, doh_config)) {
+        return Err("".into());
+    }
+
+    let mut addrs = Vec::new();
+    for qtype in [QueryType::AAAA, QueryType::A] {
+        if let Ok(ips) = Box::pin(hostname.dns_resolve(qtype)).await {
+            addrs.extend(ips.into_iter().filter_map(|ip| match ip {
+                HostName::V4(ip) => Some(SocketAddr::new(ip.into(), port)),
+                HostName::V6(ip) => Some(SocketAddr::new(ip.into(), port)),
+                HostName::Domain(_) => None,
+            }));
+        }
+    }
+    if addrs.is_empty() {
+        return Err("".into());
+    }
+
+    Ok(addrs)
+}
+
+fn request_config(doh_config: &DoHConfig) -> RequestConfig {
+    let mut req_conf = RequestConfig::new();
+    req_conf.doh = false;
+    req_conf.fake_host = match &doh_config.fake_host {
+        Some(f) => HostName::from_str(f).ok(),
+        _ => None,
+    };
+    if let Some(proxy) = PROXY.get() {
+        if let Some(1) = &proxy.config.fragment {
+            req_conf.fragment = Some(true)
+        }
+    }
+    req_conf.fragment_mode = doh_config.fragment_mode;
+
+    req_conf
+}
+
+/// Truncates `response` to fit the UDP payload size advertised by `query` (RFC 6891 6.2.5),
+/// dropping all records but OPT and setting TC
+pub fn fit_udp(query: &[u8], response: Vec<u8>) -> Result<Vec<u8>, Error> {
+    let max_size = match Message::parse(query)?.opt() {
+        Some(opt) => opt.class.max(512),
+        None => 512,
+    };
+    if response.len() <= max_size as usize {
+        return Ok(response);
+    }
+
+    let mut truncated = Message::parse(&response)?;
+    truncated.flags |= 0x0200;
+    truncated.answers.clear();
+    truncated.authorities.clear();
+    truncated
+        .additionals
+        .retain(|r| r.rtype == message::rtype::OPT);
+
+    truncated.to_vec()
+}
@@ -0,0 +1,257 @@
+//! DNS over QUIC (RFC 9250) and DNS over HTTP/3.
+//!
+//! QUIC runs over UDP, so these transports connect directly to the resolver.
+//! They can not be used with `proxies`, which would be bypassed.
+
+use super::https;
+use crate::{config::DoHConfig, utils::HostName, Error, PROXY};
+
+use bytes::{Buf, Bytes};
+use http_body_util::BodyExt;
+use hyper::{Request, Uri};
+use once_cell::sync::Lazy;
+use quinn::{crypto::rustls::QuicClientConfig, ClientConfig, Connection, Endpoint};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tokio_rustls::rustls;
+
+type H3Sender = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+/// For each connection attempt and response
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+static DOQ_CONNECTIONS: Lazy<Mutex<HashMap<String, Connection>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static DOH3_SENDERS: Lazy<Mutex<HashMap<String, H3Sender>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub async fn doq_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    // Not locked while connecting, so that a slow handshake does not hold up other endpoints
+    let cached = DOQ_CONNECTIONS
+        .lock()
+        .await
+        .get(&doh_config.endpoint)
+        .filter(|c| c.close_reason().is_none())
+        .cloned();
+    let connection = match cached {
+        Some(c) => c,
+        None => {
+            let c = connect(doh_config, endpoint, b"doq", 853).await?;
+            DOQ_CONNECTIONS
+                .lock()
+                .await
+                .insert(doh_config.endpoint.clone(), c.clone());
+            c
+        }
+    };
+
+    doq_exchange(&connection, query).await
+}
+
+async fn doq_exchange(connection: &Connection, query: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&u16::try_from(query.len())?.to_be_bytes());
+    message.extend_from_slice(query);
+    // RFC 9250 4.2.1: the Message ID must be set to 0
+    *message.get_mut(2).ok_or("")? = 0;
+    *message.get_mut(3).ok_or("")? = 0;
+
+    let (mut send, mut recv) = connection.open_bi().await?;
+    send.write_all(&message).await?;
+    send.finish()?;
+
+    let response = tokio::time::timeout(TIMEOUT, recv.read_to_end(65537)).await??;
+    let len = u16::from_be_bytes(response.get(0..2).ok_or("")?.try_into()?) as usize;
+    Ok(response.get(2..(len + 2)).ok_or("")?.to_vec())
+}
+
+pub async fn doh3_query(
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let cached = DOH3_SENDERS.lock().await.remove(&doh_config.endpoint);
+    if let Some(sender) = cached {
+        if let Ok(response) = doh3_send(sender, query, doh_config, endpoint).await {
+            return Ok(response);
+        }
+    }
+
+    let connection = connect(doh_config, endpoint, b"h3", 443).await?;
+    let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(connection)).await?;
+    tokio::spawn(async move { driver.wait_idle().await });
+
+    doh3_send(sender, query, doh_config, endpoint).await
+}
+
+async fn doh3_send(
+    mut sender: H3Sender,
+    query: &[u8],
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+) -> Result<Vec<u8>, Error> {
+    let uri = Uri::builder()
+        .scheme("https")
+        .authority(endpoint.authority().ok_or("")?.as_str())
+        .path_and_query(endpoint.path_and_query().ok_or("")?.as_str())
+        .build()?;
+    let (parts, body) = https::build_request(query, doh_config, &uri)?.into_parts();
+    let body = body.collect().await?.to_bytes();
+
+    let mut stream = sender.send_request(Request::from_parts(parts, ())).await?;
+    if !body.is_empty() {
+        stream.send_data(body).await?;
+    }
+    stream.finish().await?;
+
+    let response = stream.recv_response().await?;
+    if !response.status().is_success() {
+        return Err("".into());
+    }
+
+    let mut response_body = Vec::new();
+    while let Some(mut data) = stream.recv_data().await? {
+        while data.has_remaining() {
+            let chunk = data.chunk();
+            response_body.extend_from_slice(chunk);
+
+            let len = chunk.len();
+            data.advance(len);
+        }
+    }
+
+    DOH3_SENDERS
+        .lock()
+        .await
+        .insert(doh_config.endpoint.clone(), sender);
+
+    Ok(response_body)
+}
+
+async fn connect(
+    doh_config: &DoHConfig,
+    endpoint: &Uri,
+    alpn: &[u8],
+    default_port: u16,
+) -> Result<Connection, Error> {
+    let server_name = HostName::from_str(endpoint.host().ok_or("")?)?.to_string();
+    let connect_host = match &doh_config.fake_host {
+        Some(f) => HostName::from_str(f)?.to_string(),
+        None => server_name.clone(),
+    };
+    let port = endpoint.port_u16().unwrap_or(default_port);
+    let addrs = super::upstream_addrs(doh_config, &connect_host, port).await?;
+
+    // QUIC needs TLS 1.3
+    let connectors = &PROXY.get().ok_or("")?.tls_connectors;
+    let tls = connectors.client_config(&[&rustls::version::TLS13])?;
+    connect_to(&addrs, &server_name, tls, alpn).await
+}
+
+/// Tries `addrs` in order
+async fn connect_to(
+    addrs: &[std::net::SocketAddr],
+    server_name: &str,
+    mut tls: rustls::ClientConfig,
+    alpn: &[u8],
+) -> Result<Connection, Error> {
+    tls.alpn_protocols = vec![alpn.to_vec()];
+    let config = ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+
+    let mut result = Err("".into());
+    for addr in addrs {
+        let bind = if addr.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let mut quic = Endpoint::client(bind.parse()?)?;
+        quic.set_default_client_config(config.clone());
+
+        let connecting = quic.connect(*addr, server_name)?;
+        result = match tokio::time::timeout(TIMEOUT, connecting).await {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(e)) => Err(e.into()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::x509;
+    use quinn::{crypto::rustls::QuicServerConfig, ServerConfig};
+
+    #[tokio::test]
+    async fn doq_exchange_with_local_server() {
+        let (cert, key) = x509::self_signed(&[HostName::Domain("localhost".to_string())]).unwrap();
+        let mut server_tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key)
+            .unwrap();
+        server_tls.alpn_protocols = vec![b"doq".to_vec()];
+        let server_config =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_tls).unwrap()));
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        // Echoes the query back as a response
+        let stand_in = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+            let mut message = recv.read_to_end(65537).await.unwrap();
+            let id = [message[2], message[3]];
+            message[4] |= 0x80;
+            send.write_all(&message).await.unwrap();
+            send.finish().unwrap();
+            let _ = send.stopped().await;
+            id
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let tls = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connection = connect_to(&[addr], "localhost", tls, b"doq").await.unwrap();
+
+        let query = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let response = doq_exchange(&connection, &query).await.unwrap();
+
+        assert_eq!(stand_in.await.unwrap(), [0, 0]);
+        assert_eq!(response.len(), query.len());
+        assert_eq!(response[2], 0x81);
+        assert_eq!(&response[4..], &query[4..]);
+    }
+
+    #[tokio::test]
+    async fn connect_to_rejects_untrusted_server() {
+        let (cert, key) = x509::self_signed(&[HostName::Domain("localhost".to_string())]).unwrap();
+        let mut server_tls = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        server_tls.alpn_protocols = vec![b"doq".to_vec()];
+        let server_config =
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(server_tls).unwrap()));
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            if let Some(incoming) = server.accept().await {
+                let _ = incoming.await;
+            }
+        });
+
+        let tls = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        assert!(connect_to(&[addr], "localhost", tls, b"doq").await.is_err());
+    }
+}
@@ -0,0 +1,65 @@
+//! HTTPS records (RFC 9460), looked up for the ECH configs they publish.
+
+use super::{
+    doh_query,
+    message::{rtype, Message},
+    uncache,
+};
+use crate::Error;
+
+/// SvcParamKey of the ECHConfigList
+const KEY_ECH: u16 = 5;
+
+/// ECHConfigList published for `name` on `port`, with its TTL. `fresh` skips the cached answer
+pub async fn ech_config_list(
+    name: &str,
+    port: u16,
+    fresh: bool,
+) -> Result<Option<(Vec<u8>, u32)>, Error> {
+    // Other ports have their own owner name (RFC 9460 9.1)
+    let qname = match port {
+        443 => name.to_string(),
+        _ => format!("_{}._https.{}", port, name),
+    };
+    let query = Message::query(0, &qname, rtype::HTTPS).to_vec()?;
+    if fresh {
+        uncache(&query).await?;
+    }
+    let response = Message::parse(&doh_query(query).await?)?;
+
+    for answer in response.answers.iter().filter(|a| a.rtype == rtype::HTTPS) {
+        if let Some(ech) = ech_param(&answer.data) {
+            return Ok(Some((ech, answer.ttl)));
+        }
+    }
+    Ok(None)
+}
+
+fn ech_param(data: &[u8]) -> Option<Vec<u8>> {
+    // AliasMode records carry no parameters
+    let priority = u16::from_be_bytes(data.get(0..2)?.try_into().ok()?);
+    if priority == 0 {
+        return None;
+    }
+
+    // The target name is never compressed
+    let mut pos = 2;
+    loop {
+        let len = *data.get(pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+
+    while pos + 4 <= data.len() {
+        let key = u16::from_be_bytes([data[pos], data[pos + 1]]);
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let value = data.get((pos + 4)..(pos + 4 + len))?;
+        if key == KEY_ECH {
+            return Some(value.to_vec());
+        }
+        pos += 4 + len;
+    }
+    None
+}
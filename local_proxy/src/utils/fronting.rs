@@ -0,0 +1,36 @@
+//! Domain fronting: connections to matching domains go to a front domain instead.
+
+use super::{HostName, SuffixMatcher};
+use crate::{config::FrontingConfig, Error};
+
+use tokio_rustls::rustls::pki_types::ServerName;
+
+pub struct Fronting {
+    fronts: SuffixMatcher<HostName>,
+}
+
+impl Fronting {
+    pub fn new(config: Option<&Vec<FrontingConfig>>) -> Result<Self, Error> {
+        let mut fronts = Vec::new();
+        for rule in config.into_iter().flatten() {
+            // Sent as SNI, so it has to be a valid domain
+            let front = match ServerName::try_from(rule.front.as_str())? {
+                ServerName::DnsName(name) => HostName::Domain(name.as_ref().to_string()),
+                _ => return Err("".into()),
+            };
+            fronts.extend(rule.domains.iter().map(|d| (d, front.clone())));
+        }
+
+        Ok(Self {
+            fronts: SuffixMatcher::new(fronts),
+        })
+    }
+
+    /// Front configured for `hostname`, picked by the longest matching suffix
+    pub fn front(&self, hostname: &HostName) -> Option<HostName> {
+        match hostname {
+            HostName::Domain(d) => self.fronts.get(d).cloned(),
+            _ => None,
+        }
+    }
+}
@@ -1,9 +1,21 @@
 mod addr;
 mod dns;
+mod fronting;
 mod http;
+mod prefixed;
+mod sniff;
+mod suffix_matcher;
+pub mod tls;
 mod uri_parse;
+pub mod x509;
 
 pub use addr::{HostName, SocketAddr};
-pub use dns::doh_query;
+pub use dns::{
+    doh_query, ech_config_list, fit_udp, serve_query, Blocklist, Ecs, FakeIp, Hosts, QueryLog,
+};
+pub use fronting::Fronting;
 pub use http::Body;
+pub use prefixed::Prefixed;
+pub use sniff::{is_http_request, sni_range, sniff, Sniffed};
+pub use suffix_matcher::SuffixMatcher;
 pub use uri_parse::ParsedUri;
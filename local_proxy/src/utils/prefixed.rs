@@ -0,0 +1,57 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream that replays bytes already read from `inner` before reading from it again
+pub struct Prefixed<RW> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: RW,
+}
+
+impl<RW> Prefixed<RW> {
+    pub fn new(prefix: Vec<u8>, inner: RW) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<RW: AsyncRead + Unpin> AsyncRead for Prefixed<RW> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pos < self.prefix.len() {
+            let len = (self.prefix.len() - self.pos).min(buf.remaining());
+            buf.put_slice(&self.prefix[self.pos..(self.pos + len)]);
+            self.pos += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<RW: AsyncWrite + Unpin> AsyncWrite for Prefixed<RW> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,175 @@
+//! Reads the destination host name from the first bytes a client sends.
+
+use std::ops::Range;
+
+pub enum Sniffed {
+    Host(String),
+    /// The data so far is a prefix of something that may contain a host name
+    Incomplete,
+    NotFound,
+}
+
+pub fn sniff(buf: &[u8]) -> Sniffed {
+    match buf.first() {
+        None => Sniffed::Incomplete,
+        Some(0x16) => tls_sni(buf),
+        Some(b) if b.is_ascii_uppercase() => http_host(buf),
+        _ => Sniffed::NotFound,
+    }
+}
+
+/// Whether `buf` starts with an HTTP/1 request line
+pub fn is_http_request(buf: &[u8]) -> bool {
+    let line = match buf.iter().position(|b| *b == b'\n') {
+        Some(i) => &buf[..i],
+        None => return false,
+    };
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    let method_len = line.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    method_len > 0
+        && line.get(method_len) == Some(&b' ')
+        && (line.ends_with(b" HTTP/1.1") || line.ends_with(b" HTTP/1.0"))
+}
+
+/// Server name in a TLS ClientHello (RFC 8446 4.1.2, RFC 6066 3)
+fn tls_sni(buf: &[u8]) -> Sniffed {
+    // The ClientHello may span several records
+    let mut handshake = Vec::new();
+    let mut records = buf;
+    while records.len() >= 5 {
+        if records[0] != 0x16 {
+            return Sniffed::NotFound;
+        }
+        let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let end = (5 + len).min(records.len());
+        handshake.extend_from_slice(&records[5..end]);
+        records = &records[end..];
+    }
+
+    // Running out of data means the rest has not arrived yet
+    match sni_range(&handshake) {
+        Some(Some(range)) => match std::str::from_utf8(&handshake[range]) {
+            Ok(name) => Sniffed::Host(name.to_string()),
+            Err(_) => Sniffed::NotFound,
+        },
+        Some(None) => Sniffed::NotFound,
+        None => Sniffed::Incomplete,
+    }
+}
+
+/// Position of the server name in a ClientHello handshake message.
+/// Returns `None` when the message is truncated, and `Some(None)` when it has no server name.
+pub fn sni_range(handshake: &[u8]) -> Option<Option<Range<usize>>> {
+    let mut reader = Reader(handshake);
+    if reader.u8()? != 1 {
+        return Some(None);
+    }
+    let len = reader.u24()?;
+    let mut hello = Reader(reader.bytes(len)?);
+
+    hello.bytes(2 + 32)?; // legacy_version, random
+    let session_id = hello.u8()? as usize;
+    hello.bytes(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.bytes(cipher_suites)?;
+    let compression = hello.u8()? as usize;
+    hello.bytes(compression)?;
+
+    if hello.0.is_empty() {
+        return Some(None);
+    }
+    let extensions_len = hello.u16()? as usize;
+    let mut extensions = Reader(hello.bytes(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = Reader(extensions.bytes(len)?);
+        if extension_type != 0 {
+            continue;
+        }
+
+        let list_len = data.u16()? as usize;
+        let mut list = Reader(data.bytes(list_len)?);
+        while !list.0.is_empty() {
+            let name_type = list.u8()?;
+            let len = list.u16()? as usize;
+            let name = list.bytes(len)?;
+            if name_type == 0 {
+                let start = name.as_ptr() as usize - handshake.as_ptr() as usize;
+                return Some(Some(start..(start + name.len())));
+            }
+        }
+    }
+
+    Some(None)
+}
+
+/// `Host` header of an HTTP/1 request
+fn http_host(buf: &[u8]) -> Sniffed {
+    let method_len = buf.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    match buf.get(method_len) {
+        None if method_len < 16 => return Sniffed::Incomplete,
+        Some(b' ') => {}
+        _ => return Sniffed::NotFound,
+    }
+
+    // Only complete lines, so that a partially received header is not taken
+    let complete = match buf.iter().rposition(|b| *b == b'\n') {
+        Some(i) => &buf[..i],
+        None => return Sniffed::Incomplete,
+    };
+    let mut lines = complete.split(|b| *b == b'\n');
+    lines.next();
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            return Sniffed::NotFound;
+        }
+
+        let (name, value) = match line.iter().position(|b| *b == b':') {
+            Some(i) => (&line[..i], &line[(i + 1)..]),
+            None => continue,
+        };
+        if !name.eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+        let value = match std::str::from_utf8(value) {
+            Ok(v) => v.trim(),
+            Err(_) => return Sniffed::NotFound,
+        };
+
+        // Strips the port, keeping IPv6 literals whole
+        let host = match value.strip_prefix('[') {
+            Some(v6) => v6.split(']').next().unwrap_or(""),
+            None => value.split(':').next().unwrap_or(""),
+        };
+        return Sniffed::Host(host.to_string());
+    }
+
+    Sniffed::Incomplete
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.0.get(..len)?;
+        self.0 = &self.0[len..];
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.bytes(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.bytes(3)?;
+        Some(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
@@ -0,0 +1,54 @@
+//! Matching of domains together with their subdomains.
+
+use std::collections::HashMap;
+
+/// Domains with values, looked up by a name equal to or under one of them
+pub struct SuffixMatcher<T = ()> {
+    domains: HashMap<String, T>,
+}
+
+impl<T> SuffixMatcher<T> {
+    /// Later entries replace earlier ones for the same domain
+    pub fn new<S: AsRef<str>>(entries: impl IntoIterator<Item = (S, T)>) -> Self {
+        Self {
+            domains: entries
+                .into_iter()
+                .map(|(domain, value)| (normalize(domain.as_ref()), value))
+                .collect(),
+        }
+    }
+
+    /// Value of the longest domain that `name` is equal to or under
+    pub fn get(&self, name: &str) -> Option<&T> {
+        let name = normalize(name);
+        let mut suffix = name.as_str();
+        loop {
+            if let Some(value) = self.domains.get(suffix) {
+                return Some(value);
+            }
+            suffix = suffix.split_once('.')?.1;
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+}
+
+impl<T> Default for SuffixMatcher<T> {
+    fn default() -> Self {
+        Self {
+            domains: HashMap::new(),
+        }
+    }
+}
+
+impl SuffixMatcher {
+    pub fn from_domains<S: AsRef<str>>(domains: impl IntoIterator<Item = S>) -> Self {
+        Self::new(domains.into_iter().map(|domain| (domain, ())))
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,52 @@
+use crate::{
+    config::TlsServerConfig,
+    utils::{x509, HostName},
+    Error,
+};
+
+use std::{sync::Arc, time::Duration};
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+};
+
+/// For the TLS handshake of inbound connections
+pub const ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Loads the PEM certificate chain and private key, offering `alpn` to clients
+pub fn server_config(
+    config: &TlsServerConfig,
+    alpn: &[&[u8]],
+) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let certs = CertificateDer::pem_file_iter(&config.cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&config.key)?;
+
+    with_cert(certs, key, alpn)
+}
+
+/// Loads the certificate, or generates a self-signed one for `names` there when it is missing,
+/// and also returns its fingerprint
+pub fn self_signed_server_config(
+    cert: &str,
+    key: &str,
+    names: &[HostName],
+    alpn: &[&[u8]],
+) -> Result<(Arc<rustls::ServerConfig>, String), Error> {
+    let (certs, key) = x509::load_or_generate_self_signed(cert, key, names)?;
+    let fingerprint = x509::fingerprint(certs.first().ok_or("")?);
+
+    Ok((with_cert(certs, key, alpn)?, fingerprint))
+}
+
+fn with_cert(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    alpn: &[&[u8]],
+) -> Result<Arc<rustls::ServerConfig>, Error> {
+    let mut server = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    server.alpn_protocols = alpn.iter().map(|a| a.to_vec()).collect();
+
+    Ok(Arc::new(server))
+}
@@ -0,0 +1,503 @@
+//! Minimal X.509 certificates with ECDSA P-256 keys, for the ones generated at runtime
+
+use crate::{utils::HostName, Error};
+
+use aws_lc_rs::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use base64::Engine;
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::rustls::{
+    crypto::aws_lc_rs::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    sign::CertifiedKey,
+};
+
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+const OID_EXT_KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x25];
+const OID_SERVER_AUTH: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+
+/// Certificates are valid from a day ago, for about a year
+const VALIDITY: Duration = Duration::from_secs(365 * 86400);
+const CA_VALIDITY: Duration = Duration::from_secs(10 * 365 * 86400);
+
+/// Certificate authority issuing server certificates
+pub struct Ca {
+    key: EcdsaKeyPair,
+    name: Vec<u8>,
+}
+
+impl Ca {
+    /// Loads the PEM certificate and ECDSA P-256 PKCS#8 key, or generates them there when both are missing
+    pub fn load_or_generate(cert: &str, key: &str) -> Result<Self, Error> {
+        if !Path::new(cert).exists() && !Path::new(key).exists() {
+            return Self::generate(cert, key);
+        }
+
+        let cert = CertificateDer::from_pem_file(cert)?;
+        let key = match PrivateKeyDer::from_pem_file(key)? {
+            PrivateKeyDer::Pkcs8(key) => key,
+            _ => return Err("".into()),
+        };
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key.secret_pkcs8_der())?;
+        // Certificates signed with another key would only fail on the clients
+        if public_key(&cert) != Some(key.public_key().as_ref()) {
+            return Err("".into());
+        }
+
+        Ok(Self {
+            key,
+            name: subject(&cert).ok_or("")?.to_vec(),
+        })
+    }
+
+    fn generate(cert_path: &str, key_path: &str) -> Result<Self, Error> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())?;
+        let name = name("local_proxy_rs CA");
+        let cert = certificate(&name, &[], true, key.public_key().as_ref(), &name, &key)?;
+
+        save(cert_path, key_path, &cert, pkcs8.as_ref())?;
+
+        Ok(Self { key, name })
+    }
+
+    /// Issues a server certificate for `names`, returned with the key and the chain up to this CA
+    pub fn issue(
+        &self,
+        names: &[HostName],
+    ) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())?;
+
+        let subject = name(&names.first().ok_or("")?.to_string());
+        let public_key = key.public_key().as_ref();
+        let cert = certificate(&subject, names, false, public_key, &self.name, &self.key)?;
+        let key = PrivatePkcs8KeyDer::from(pkcs8.as_ref().to_vec());
+
+        Ok((vec![cert], PrivateKeyDer::Pkcs8(key)))
+    }
+}
+
+/// Generates a self-signed server certificate for `names`
+pub fn self_signed(
+    names: &[HostName],
+) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>), Error> {
+    // A certificate without names is valid for nothing
+    let subject = name(&names.first().ok_or("")?.to_string());
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())?;
+
+    let public_key = key.public_key().as_ref();
+    let cert = certificate(&subject, names, false, public_key, &subject, &key)?;
+    let key = PrivatePkcs8KeyDer::from(pkcs8.as_ref().to_vec());
+
+    Ok((cert, PrivateKeyDer::Pkcs8(key)))
+}
+
+/// Loads the PEM certificate chain and private key, or generates a self-signed certificate for `names`
+/// there when both are missing
+pub fn load_or_generate_self_signed(
+    cert: &str,
+    key: &str,
+    names: &[HostName],
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    if !Path::new(cert).exists() && !Path::new(key).exists() {
+        let (cert_der, key_der) = self_signed(names)?;
+        save(cert, key, &cert_der, key_der.secret_der())?;
+        return Ok((vec![cert_der], key_der));
+    }
+
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    // A key of another certificate would only fail in the handshakes
+    let signing_key = default_provider()
+        .key_provider
+        .load_private_key(key.clone_key())?;
+    CertifiedKey::new(certs.clone(), signing_key).keys_match()?;
+
+    Ok((certs, key))
+}
+
+/// Writes the certificate, and the PKCS#8 key readable only by the owner
+fn save(cert_path: &str, key_path: &str, cert: &[u8], pkcs8: &[u8]) -> Result<(), Error> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(key_path)?
+        .write_all(pem("PRIVATE KEY", pkcs8).as_bytes())?;
+    fs::write(cert_path, pem("CERTIFICATE", cert))?;
+
+    Ok(())
+}
+
+/// SHA-256 of the certificate, in the colon separated hex browsers show
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    digest::digest(&digest::SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// `subject` and `issuer` are encoded Names
+fn certificate(
+    subject: &[u8],
+    names: &[HostName],
+    ca: bool,
+    public_key: &[u8],
+    issuer: &[u8],
+    issuer_key: &EcdsaKeyPair,
+) -> Result<CertificateDer<'static>, Error> {
+    let rng = SystemRandom::new();
+    let mut serial = [0; 16];
+    rng.fill(&mut serial)?;
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
+    let not_before = now.saturating_sub(Duration::from_secs(86400));
+    let not_after = now + if ca { CA_VALIDITY } else { VALIDITY };
+    let validity = [utc_time(not_before), utc_time(not_after)].concat();
+
+    let spki = [
+        tlv(
+            0x30,
+            &[tlv(0x06, OID_EC_PUBLIC_KEY), tlv(0x06, OID_P256)].concat(),
+        ),
+        tlv(0x03, &[&[0], public_key].concat()),
+    ]
+    .concat();
+
+    let extensions = if ca {
+        [
+            extension(OID_BASIC_CONSTRAINTS, true, &tlv(0x30, &tlv(0x01, &[0xff]))),
+            // keyCertSign and cRLSign
+            extension(OID_KEY_USAGE, true, &tlv(0x03, &[0x01, 0x06])),
+        ]
+        .concat()
+    } else {
+        let alt_names = names
+            .iter()
+            .map(|name| match name {
+                HostName::Domain(domain) => tlv(0x82, domain.as_bytes()),
+                HostName::V4(ip) => tlv(0x87, &ip.octets()),
+                HostName::V6(ip) => tlv(0x87, &ip.octets()),
+            })
+            .collect::<Vec<_>>()
+            .concat();
+        [
+            extension(OID_BASIC_CONSTRAINTS, true, &tlv(0x30, &[])),
+            // digitalSignature
+            extension(OID_KEY_USAGE, true, &tlv(0x03, &[0x07, 0x80])),
+            extension(
+                OID_EXT_KEY_USAGE,
+                false,
+                &tlv(0x30, &tlv(0x06, OID_SERVER_AUTH)),
+            ),
+            extension(OID_SUBJECT_ALT_NAME, false, &tlv(0x30, &alt_names)),
+        ]
+        .concat()
+    };
+
+    let algorithm = tlv(0x30, &tlv(0x06, OID_ECDSA_WITH_SHA256));
+    let tbs = tlv(
+        0x30,
+        &[
+            tlv(0xa0, &tlv(0x02, &[2])),
+            tlv(0x02, &serial),
+            algorithm.clone(),
+            issuer.to_vec(),
+            tlv(0x30, &validity),
+            subject.to_vec(),
+            tlv(0x30, &spki),
+            tlv(0xa3, &tlv(0x30, &extensions)),
+        ]
+        .concat(),
+    );
+
+    let signature = issuer_key.sign(&rng, &tbs)?;
+    let cert = tlv(
+        0x30,
+        &[
+            tbs,
+            algorithm,
+            tlv(0x03, &[&[0], signature.as_ref()].concat()),
+        ]
+        .concat(),
+    );
+
+    Ok(CertificateDer::from(cert))
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut der = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        der.push(0x80 | (bytes.len() - skip) as u8);
+        der.extend_from_slice(&bytes[skip..]);
+    }
+    der.extend_from_slice(value);
+
+    der
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    let attribute = tlv(
+        0x30,
+        &[
+            tlv(0x06, OID_COMMON_NAME),
+            tlv(0x0c, common_name.as_bytes()),
+        ]
+        .concat(),
+    );
+    tlv(0x30, &tlv(0x31, &attribute))
+}
+
+/// The encoded subject Name of `cert`
+fn subject(cert: &[u8]) -> Option<&[u8]> {
+    tbs_field(cert, 4)
+}
+
+/// The subjectPublicKey of `cert`, without the unused bits octet
+fn public_key(cert: &[u8]) -> Option<&[u8]> {
+    let (_, spki, _) = read_tlv(tbs_field(cert, 5)?)?;
+    let (_, _, rest) = read_tlv(spki)?;
+    let (_, bits, _) = read_tlv(rest)?;
+
+    bits.strip_prefix(&[0])
+}
+
+/// The TLV at `index` in the TBSCertificate of `cert`, counting from the serial number
+fn tbs_field(cert: &[u8], index: usize) -> Option<&[u8]> {
+    let (_, cert, _) = read_tlv(cert)?;
+    let (_, tbs, _) = read_tlv(cert)?;
+
+    let mut rest = tbs;
+    // Skips the version when it is there
+    let mut skip = if tbs.first() == Some(&0xa0) {
+        index + 1
+    } else {
+        index
+    };
+    loop {
+        let (tlv, _, next) = read_tlv(rest)?;
+        if skip == 0 {
+            return Some(tlv);
+        }
+        skip -= 1;
+        rest = next;
+    }
+}
+
+/// Splits `der` into its first TLV, that TLV's value, and the rest
+fn read_tlv(der: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+    let first = *der.get(1)? as usize;
+    let (len, header) = match first {
+        0..=0x7f => (first, 2),
+        0x81..=0x84 => {
+            let bytes = der.get(2..(2 + (first & 0x7f)))?;
+            let len = bytes.iter().fold(0, |len, b| (len << 8) | *b as usize);
+            (len, 2 + bytes.len())
+        }
+        _ => return None,
+    };
+
+    let end = header.checked_add(len).filter(|end| *end <= der.len())?;
+    Some((&der[..end], &der[header..end], &der[end..]))
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+
+    pem
+}
+
+fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut content = tlv(0x06, oid);
+    if critical {
+        content.extend_from_slice(&tlv(0x01, &[0xff]));
+    }
+    content.extend_from_slice(&tlv(0x04, value));
+
+    tlv(0x30, &content)
+}
+
+/// UTCTime, which covers the years until 2049
+fn utc_time(since_epoch: Duration) -> Vec<u8> {
+    let secs = since_epoch.as_secs();
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    let time = format!(
+        "{:02}{:02}{:02}{:02}{:02}{:02}Z",
+        year % 100,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    tlv(0x17, time.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{
+        client::{danger::ServerCertVerifier, WebPkiServerVerifier},
+        pki_types::{ServerName, UnixTime},
+        RootCertStore,
+    };
+
+    fn verify(
+        roots: Vec<CertificateDer<'static>>,
+        chain: &[CertificateDer<'static>],
+        name: &str,
+    ) -> bool {
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root).unwrap();
+        }
+        let verifier = WebPkiServerVerifier::builder(Arc::new(store))
+            .build()
+            .unwrap();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+
+        verifier
+            .verify_server_cert(&chain[0], &chain[1..], &name, &[], UnixTime::now())
+            .is_ok()
+    }
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("local_proxy_x509_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn self_signed_verifies_for_its_names() {
+        let names = [
+            HostName::Domain("localhost".to_string()),
+            HostName::from(std::net::Ipv4Addr::LOCALHOST),
+        ];
+        let (cert, _) = self_signed(&names).unwrap();
+        let chain = [cert.clone()];
+
+        assert!(verify(vec![cert.clone()], &chain, "localhost"));
+        assert!(verify(vec![cert.clone()], &chain, "127.0.0.1"));
+        assert!(!verify(vec![cert], &chain, "example.com"));
+    }
+
+    #[test]
+    fn ca_issues_verifiable_certificates() {
+        let (cert, key) = (temp_path("ca.pem"), temp_path("ca.key"));
+        let ca = Ca::load_or_generate(&cert, &key).unwrap();
+        let root = CertificateDer::from_pem_file(&cert).unwrap();
+        let (chain, _) = ca
+            .issue(&[HostName::Domain("example.com".to_string())])
+            .unwrap();
+
+        assert!(verify(vec![root.clone()], &chain, "example.com"));
+        assert!(!verify(vec![root], &chain, "example.org"));
+
+        // Loaded again, the CA keeps issuing under the same root
+        let ca = Ca::load_or_generate(&cert, &key).unwrap();
+        let root = CertificateDer::from_pem_file(&cert).unwrap();
+        let (chain, _) = ca
+            .issue(&[HostName::Domain("example.com".to_string())])
+            .unwrap();
+        assert!(verify(vec![root], &chain, "example.com"));
+
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[test]
+    fn ca_rejects_key_of_another_certificate() {
+        let (cert, key) = (temp_path("ca2.pem"), temp_path("ca2.key"));
+        let (other_cert, other_key) = (temp_path("ca3.pem"), temp_path("ca3.key"));
+        Ca::load_or_generate(&cert, &key).unwrap();
+        Ca::load_or_generate(&other_cert, &other_key).unwrap();
+
+        assert!(Ca::load_or_generate(&cert, &other_key).is_err());
+
+        for path in [cert, key, other_cert, other_key] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn self_signed_needs_a_name() {
+        assert!(self_signed(&[]).is_err());
+    }
+
+    #[test]
+    fn self_signed_rejects_key_of_another_certificate() {
+        let (cert, key) = (temp_path("server2.pem"), temp_path("server2.key"));
+        let (other_cert, other_key) = (temp_path("server3.pem"), temp_path("server3.key"));
+        let names = [HostName::Domain("localhost".to_string())];
+        load_or_generate_self_signed(&cert, &key, &names).unwrap();
+        load_or_generate_self_signed(&other_cert, &other_key, &names).unwrap();
+
+        assert!(load_or_generate_self_signed(&cert, &other_key, &names).is_err());
+
+        for path in [cert, key, other_cert, other_key] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn self_signed_is_kept_across_loads() {
+        let (cert, key) = (temp_path("server.pem"), temp_path("server.key"));
+        let names = [HostName::Domain("localhost".to_string())];
+        let (first, _) = load_or_generate_self_signed(&cert, &key, &names).unwrap();
+        let (second, _) = load_or_generate_self_signed(&cert, &key, &names).unwrap();
+
+        assert_eq!(first, second);
+        assert!(verify(first, &second, "localhost"));
+
+        fs::remove_file(cert).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+}